@@ -1,6 +1,12 @@
+use crate::file_lister::ScanProgress;
+use crate::http::compatibility::{AGENT_VERSION, PROTOCOL_VERSION};
+use crate::http::connection::HubConnectionStatus;
+use crate::http::grpc::tidybee_events;
 use gethostname::gethostname;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::SystemTime;
+use sysinfo::{Pid, System};
 use tracing::info;
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -9,13 +15,55 @@ struct AgentVersion {
     minimal_version: String,
 }
 
+/// Files indexed below a watched directory
+#[derive(Debug, Serialize, Clone)]
+pub struct RootStats {
+    pub path: PathBuf,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Where the agent stands with the events it has to send
+#[derive(Debug, Serialize, Clone)]
+pub struct SyncStats {
+    pub last_event_at: Option<SystemTime>,
+    pub last_event_sent_at: Option<SystemTime>,
+    pub pending_events: u64,
+    /// Sequence of the last event numbered for the Hub
+    pub last_sequence: u64,
+    /// Sequence up to which the Hub holds every event
+    pub acknowledged_sequence: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ProcessUsage {
+    pub cpu_percent: f32,
+    pub memory_bytes: u64,
+    pub virtual_memory_bytes: u64,
+    pub disk_read_bytes: u64,
+    pub disk_written_bytes: u64,
+}
+
+// Only the local API fills the optional parts, the Hub gets the rest when the agent registers
 #[derive(Serialize, Clone)]
 pub struct AgentData {
     agent_version: AgentVersion,
     machine_name: String,
     process_id: u32,
     uptime: u64,
+    agent_started_at: SystemTime,
+    agent_uptime: u64,
     watched_directories: Vec<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roots: Option<Vec<RootStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    initial_scan: Option<ScanProgress>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sync: Option<SyncStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hub: Option<HubConnectionStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    process: Option<ProcessUsage>,
 }
 
 #[allow(dead_code)]
@@ -33,7 +81,14 @@ impl AgentData {
             machine_name: gethostname().to_str().unwrap().to_owned(),
             process_id: sysinfo::get_current_pid().unwrap().as_u32(),
             uptime: sysinfo::System::uptime(),
+            agent_started_at: SystemTime::now(),
+            agent_uptime: 0,
             watched_directories: directories_watch_args,
+            roots: None,
+            initial_scan: None,
+            sync: None,
+            hub: None,
+            process: None,
         }
     }
 
@@ -58,5 +113,60 @@ impl AgentData {
 
     pub fn update(&mut self) {
         self.uptime = sysinfo::System::uptime();
+        self.agent_uptime = self
+            .agent_started_at
+            .elapsed()
+            .map_or(0, |uptime| uptime.as_secs());
+    }
+
+    pub fn set_watched_directories(&mut self, directories: Vec<PathBuf>) {
+        self.watched_directories = directories;
+    }
+
+    pub fn set_roots(&mut self, roots: Vec<RootStats>) {
+        self.roots = Some(roots);
+    }
+
+    pub fn set_initial_scan(&mut self, progress: ScanProgress) {
+        self.initial_scan = Some(progress);
+    }
+
+    pub fn set_sync(&mut self, sync: SyncStats) {
+        self.sync = Some(sync);
+    }
+
+    pub fn set_hub(&mut self, hub: HubConnectionStatus) {
+        self.hub = Some(hub);
+    }
+
+    /// What the agent tells the Hub in its heartbeats
+    pub fn to_message(&self, pending_events: u64) -> tidybee_events::AgentData {
+        tidybee_events::AgentData {
+            agent_version: AGENT_VERSION.to_owned(),
+            machine_name: self.machine_name.clone(),
+            process_id: self.process_id,
+            uptime: self.uptime,
+            watched_directories: self
+                .watched_directories
+                .iter()
+                .map(|directory| directory.display().to_string())
+                .collect(),
+            agent_uptime: self.agent_uptime,
+            pending_events,
+            protocol_version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// CPU usage is measured since the previous refresh of `system`
+    pub fn refresh_process(&mut self, system: &mut System) {
+        let pid = Pid::from_u32(self.process_id);
+        system.refresh_process(pid);
+        self.process = system.process(pid).map(|process| ProcessUsage {
+            cpu_percent: process.cpu_usage(),
+            memory_bytes: process.memory(),
+            virtual_memory_bytes: process.virtual_memory(),
+            disk_read_bytes: process.disk_usage().total_read_bytes,
+            disk_written_bytes: process.disk_usage().total_written_bytes,
+        });
     }
 }
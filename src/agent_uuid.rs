@@ -1,21 +1,72 @@
-use std::fs::{write, File};
-use std::io::prelude::*;
+use std::fs::{read_to_string, remove_file};
+use tracing::warn;
 
 use crate::error::AgentError;
+use crate::state::StateDir;
 
-pub fn get_uuid() -> Result<String, AgentError> {
-    let mut f = File::open("config/uuid")?;
-    let mut buf = [0; 36];
-    f.read_exact(&mut buf)?;
-    Ok(String::from_utf8_lossy(&buf).to_string())
+const UUID_FILE: &str = "uuid";
+
+// Hyphenated form handed out by the Hub, e.g. 67e55044-10b1-426f-9247-bb680e5fe0c8
+pub fn is_valid_uuid(uuid: &str) -> bool {
+    uuid.len() == 36
+        && uuid.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
+}
+
+pub fn migrate(state: &StateDir) {
+    state.migrate_legacy(UUID_FILE, |contents| {
+        std::str::from_utf8(contents).is_ok_and(|uuid| is_valid_uuid(uuid.trim()))
+    });
 }
 
-pub fn set_uuid(uuid: String) -> Result<(), AgentError> {
-    // remove leading and trailing double quotes
-    let mut uuid_chars = uuid.chars();
-    uuid_chars.next();
-    uuid_chars.next_back();
+pub fn get_uuid(state: &StateDir) -> Result<String, AgentError> {
+    let path = state.file(UUID_FILE);
+    let uuid = read_to_string(&path)?.trim().to_owned();
+    if !is_valid_uuid(&uuid) {
+        warn!("Ignoring invalid agent id stored in {}", path.display());
+        return Err(AgentError::InvalidAgentId(path));
+    }
+    Ok(uuid)
+}
+
+pub fn set_uuid(state: &StateDir, uuid: &str) -> Result<(), AgentError> {
+    if !is_valid_uuid(uuid) {
+        return Err(AgentError::InvalidAgentId(state.file(UUID_FILE)));
+    }
+    state.write_atomic(UUID_FILE, uuid.as_bytes())
+}
 
-    write("config/uuid", uuid_chars.as_str())?;
+pub fn remove_uuid(state: &StateDir) -> Result<(), AgentError> {
+    remove_file(state.file(UUID_FILE))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn uuid_validation() {
+        assert!(is_valid_uuid("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!is_valid_uuid("67e55044-10b1-426f-9247-bb680e5fe0c"));
+        assert!(!is_valid_uuid("67e55044x10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!is_valid_uuid("\"67e55044-10b1-426f-9247-bb680e5fe0\""));
+    }
+
+    #[test]
+    fn uuid_round_trip_in_state_dir() {
+        let dir = TempDir::new("agent-state");
+        let state = StateDir::open(Some(dir.path())).unwrap();
+
+        assert!(get_uuid(&state).is_err());
+        assert!(set_uuid(&state, "not-a-uuid").is_err());
+        set_uuid(&state, "67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap();
+        assert_eq!(
+            get_uuid(&state).unwrap(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+use crate::configuration::{Configuration, SimilarityConfig};
+use crate::error::AgentError;
+use crate::file_info::{get_file_signature, FileInfo};
+use crate::validation::{error_count, log_diagnostics, validate};
+use crate::{file_lister, hot_reload, hub_client, logging, run};
+use clap::{Parser, Subcommand, ValueEnum};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::sync::watch;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// Purpose-built file watcher for the TidyBee solution
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Configuration file layered over the files of the config directory
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Terminal log level: trace, debug, info, warn or error
+    #[arg(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Refuse to start when the configuration has errors instead of falling back to defaults
+    #[arg(long, global = true)]
+    pub strict: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Watch the configured directories and stream events to the Hub (default)
+    Run,
+    /// List the files of directories once and print them
+    Scan {
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+    },
+    /// Print the content hash of a file, as sent to the Hub
+    Hash { file: PathBuf },
+    /// Query the local API of a running agent
+    Status {
+        /// Address of the agent, defaults to server_config.address
+        #[arg(long)]
+        address: Option<String>,
+    },
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Register the agent to the Hub and print its id
+    Register {
+        /// Connection attempts before giving up, 0 to keep trying
+        #[arg(long, default_value_t = 1)]
+        attempts: u32,
+    },
+    /// Unregister the agent from the Hub and forget its id
+    Unregister,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Load the configuration and report problems
+    Check,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Json,
+    Table,
+}
+
+pub async fn execute(cli: Cli) -> Result<(), AgentError> {
+    let config = load(cli.config.as_deref(), cli.log_level.as_deref())?;
+    let command = cli.command.unwrap_or(Command::Run);
+
+    // Only the daemon logs to stdout, one-shot commands keep it for their output
+    if !matches!(command, Command::Run) {
+        logging::init_terminal(
+            cli.log_level.as_deref().unwrap_or("warn"),
+            BoxMakeWriter::new(std::io::stderr),
+        );
+    }
+
+    match command {
+        Command::Run => {
+            let logging = logging::init(&config, BoxMakeWriter::new(std::io::stdout));
+            let diagnostics = validate(&config);
+            log_diagnostics(&diagnostics);
+            if cli.strict && error_count(&diagnostics) > 0 {
+                return Err(AgentError::ConfigurationRejected(error_count(&diagnostics)));
+            }
+
+            let (config_sender, config_updates) = watch::channel(config);
+            let (config_path, cli_log_level) = (cli.config, cli.log_level);
+            let config_handle = hot_reload::spawn(
+                Configuration::files(config_path.as_deref()),
+                move || load(config_path.as_deref(), cli_log_level.as_deref()),
+                config_sender,
+            );
+            run(config_updates, config_handle, logging).await
+        }
+        Command::Scan { dirs, format } => scan(dirs, format, &config.similarity_config),
+        Command::Hash { file } => {
+            println!("{}  {}", get_file_signature(&file)?, file.display());
+            Ok(())
+        }
+        Command::Status { address } => {
+            let auth = &config.server_config.auth;
+            let api_key = auth.read_keys.first().or(auth.admin_keys.first());
+            status(
+                address.unwrap_or(config.server_config.address.clone()),
+                api_key.map(String::as_str),
+            )
+            .await
+        }
+        Command::Config(ConfigCommand::Check) => {
+            let diagnostics = validate(&config);
+            for diagnostic in &diagnostics {
+                println!("{diagnostic}");
+            }
+            match error_count(&diagnostics) {
+                0 => {
+                    println!("Configuration is valid");
+                    Ok(())
+                }
+                errors => Err(AgentError::ConfigurationRejected(errors)),
+            }
+        }
+        Command::Register { attempts } => {
+            let mut config = config;
+            config.hub_config.connection_attempt_limit = attempts;
+            let agent_id = hub_client(&config)?
+                .connect()
+                .await
+                .map_err(|err| AgentError::HubConnectionFailed(err.to_string()))?;
+            println!("{agent_id}");
+            Ok(())
+        }
+        Command::Unregister => {
+            let agent_id = hub_client(&config)?
+                .unregister()
+                .await
+                .map_err(|err| AgentError::HubConnectionFailed(err.to_string()))?;
+            println!("{agent_id}");
+            Ok(())
+        }
+    }
+}
+
+// Command-line flags take precedence over every configuration source
+fn load(config_path: Option<&Path>, log_level: Option<&str>) -> Result<Configuration, AgentError> {
+    let mut config = Configuration::init(config_path)?;
+    if let Some(log_level) = log_level {
+        config.logger_config.term_level = log_level.to_owned();
+    }
+    Ok(config)
+}
+
+fn scan(
+    dirs: Vec<PathBuf>,
+    format: OutputFormat,
+    similarity: &SimilarityConfig,
+) -> Result<(), AgentError> {
+    let files = file_lister::list_directories(dirs, similarity)?;
+    match format {
+        OutputFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&files).expect("FileInfo is always serializable")
+        ),
+        OutputFormat::Table => print_table(&files),
+    }
+    Ok(())
+}
+
+fn print_table(files: &[FileInfo]) {
+    println!("{:>12}  {:<20}  {:<39}  PATH", "SIZE", "MODIFIED", "HASH");
+    for file in files {
+        println!(
+            "{:>12}  {:<20}  {:<39}  {}",
+            file.size,
+            humantime::format_rfc3339_seconds(file.last_modified).to_string(),
+            file.hash.as_deref().unwrap_or("-"),
+            file.pretty_path.display()
+        );
+    }
+}
+
+async fn status(address: String, api_key: Option<&str>) -> Result<(), AgentError> {
+    // An agent listening on every interface is reached through the loopback
+    let address = match address.parse::<SocketAddr>() {
+        Ok(addr) if addr.ip().is_unspecified() => format!("127.0.0.1:{}", addr.port()),
+        _ => address,
+    };
+
+    let client = reqwest::Client::new();
+    let mut status = serde_json::Map::new();
+    for (key, route) in [("agent", "get_status"), ("hub", "hub_status")] {
+        let mut request = client.get(format!("http://{address}/{route}"));
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        let value = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|err| AgentError::AgentUnreachable(address.clone(), err.to_string()))?
+            .json::<serde_json::Value>()
+            .await
+            .map_err(|err| AgentError::AgentUnreachable(address.clone(), err.to_string()))?;
+        status.insert(key.to_owned(), value);
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&status).expect("JSON values are always serializable")
+    );
+    Ok(())
+}
@@ -0,0 +1,431 @@
+use crate::configuration::{Configuration, HubConfig};
+use crate::file_index::FileIndex;
+use crate::file_info::{create_file_info, FileInfo};
+use crate::file_lister;
+use crate::hot_reload::ConfigHandle;
+use crate::http::connection::Backoff;
+use crate::http::grpc::tidybee_events::{
+    hub_command::Command, CommandResult, CommandState, FileAction, FileActionCommand,
+    FileEventRequest, HubCommand,
+};
+use crate::http::grpc::{created_event, GrpcClient};
+use crate::http::hub::Hub;
+use crate::watched_roots;
+use std::fs;
+use std::future;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Instant};
+use tonic::{Code, Streaming};
+use tracing::{info, warn};
+
+pub enum ChannelEvent {
+    Command(HubCommand),
+    /// Time to try opening the channel again
+    Reopen,
+}
+
+/// Stream of commands from the Hub, opened again with backoff when it breaks
+pub struct CommandChannel {
+    open: Option<(mpsc::Sender<CommandResult>, Streaming<HubCommand>)>,
+    backoff: Backoff,
+    // Never when the Hub does not support commands
+    reopen_at: Option<Instant>,
+}
+
+impl CommandChannel {
+    pub fn new(hub_config: &HubConfig) -> Self {
+        Self {
+            open: None,
+            backoff: Backoff::from_config(hub_config),
+            reopen_at: Some(Instant::now()),
+        }
+    }
+
+    pub async fn next(&mut self) -> ChannelEvent {
+        if let Some((_, commands)) = &mut self.open {
+            match commands.message().await {
+                Ok(Some(command)) => return ChannelEvent::Command(command),
+                Ok(None) => warn!("The Hub closed the command channel"),
+                Err(status) => warn!("The command channel broke: {}", status),
+            }
+            self.open = None;
+            self.reopen_at = Some(Instant::now() + self.backoff.next_delay());
+        }
+        match self.reopen_at {
+            Some(reopen_at) => {
+                sleep_until(reopen_at).await;
+                ChannelEvent::Reopen
+            }
+            None => future::pending().await,
+        }
+    }
+
+    pub async fn open(&mut self, grpc_client: &mut GrpcClient) {
+        match grpc_client.open_command_channel().await {
+            Ok(open) => {
+                info!("Listening for commands from the Hub");
+                self.backoff.reset();
+                self.open = Some(open);
+            }
+            Err(status) if status.code() == Code::Unimplemented => {
+                info!("The Hub does not send commands");
+                self.reopen_at = None;
+            }
+            Err(status) => {
+                let delay = self.backoff.next_delay();
+                warn!(
+                    "Could not open the command channel: {}, retrying in {:?}",
+                    status, delay
+                );
+                self.reopen_at = Some(Instant::now() + delay);
+            }
+        }
+    }
+
+    /// Drops the channel to open it again right away, such as after connecting to another Hub
+    pub fn reset(&mut self, hub_config: &HubConfig) {
+        *self = Self::new(hub_config);
+    }
+
+    pub async fn send(&self, result: CommandResult) {
+        if let Some((results, _)) = &self.open {
+            if results.send(result).await.is_err() {
+                warn!("Could not send a command result, the channel is closed");
+            }
+        }
+    }
+}
+
+pub fn accepted(command: &HubCommand) -> CommandResult {
+    CommandResult {
+        command_id: command.command_id.clone(),
+        state: CommandState::Accepted as i32,
+        ..Default::default()
+    }
+}
+
+#[derive(Default)]
+pub struct Outcome {
+    files: Vec<FileEventRequest>,
+    file_count: u64,
+    // What a scan listed, for the index and the Hub
+    listed: Option<(Vec<PathBuf>, Vec<FileInfo>)>,
+}
+
+/// The id of a command and how it went
+pub type Execution = (String, Result<Outcome, String>);
+
+/// Runs a command from the Hub off the async workers, as scans and hashing can take a while
+pub async fn execute(
+    command: HubCommand,
+    config: Configuration,
+    index: FileIndex,
+    config_handle: ConfigHandle,
+) -> Execution {
+    info!("Running command {} from the Hub", command.command_id);
+    let command_id = command.command_id;
+    let outcome =
+        tokio::task::spawn_blocking(move || run(command.command, &config, &index, &config_handle))
+            .await
+            .unwrap_or_else(|err| Err(err.to_string()));
+    (command_id, outcome)
+}
+
+fn run(
+    command: Option<Command>,
+    config: &Configuration,
+    index: &FileIndex,
+    config_handle: &ConfigHandle,
+) -> Result<Outcome, String> {
+    match command {
+        Some(Command::Rescan(rescan)) => scan(&rescan.path, config, false),
+        Some(Command::Rehash(rehash)) => scan(&rehash.path, config, true),
+        Some(Command::AddWatchRoot(root)) => add_watch_root(&root.path, config, config_handle),
+        Some(Command::RemoveWatchRoot(root)) => {
+            remove_watch_root(&root.path, config, config_handle)
+        }
+        Some(Command::FileDetails(details)) => file_details(&details.path, config, index),
+        Some(Command::FileAction(action)) => file_action(action, config, config_handle),
+        None => Err("Unknown command".to_owned()),
+    }
+}
+
+/// Indexes and sends what a scan listed, returning the result of the command
+pub async fn finish(
+    (command_id, outcome): Execution,
+    hub: &mut Hub,
+    index: &FileIndex,
+) -> CommandResult {
+    let outcome = match outcome {
+        Ok(mut outcome) => match outcome.listed.take() {
+            Some((scanned, files)) => {
+                index.replace(&scanned, files.clone());
+                hub.grpc_client
+                    .send_create_events_once(files)
+                    .await
+                    .map(|()| outcome)
+                    .map_err(|err| err.to_string())
+            }
+            None => Ok(outcome),
+        },
+        Err(error) => Err(error),
+    };
+
+    match outcome {
+        Ok(outcome) => CommandResult {
+            command_id,
+            state: CommandState::Completed as i32,
+            error: None,
+            files: outcome.files,
+            file_count: outcome.file_count,
+        },
+        Err(error) => {
+            warn!("Command {} failed: {}", command_id, error);
+            CommandResult {
+                command_id,
+                state: CommandState::Failed as i32,
+                error: Some(error),
+                ..Default::default()
+            }
+        }
+    }
+}
+
+// Lists and hashes the files at `path`, every watched directory when it is empty
+fn scan(path: &str, config: &Configuration, with_details: bool) -> Result<Outcome, String> {
+    let (scanned, paths) = if path.is_empty() {
+        (
+            watched_roots(config),
+            config.filesystem_interface_config.dir.clone(),
+        )
+    } else {
+        let path = watched_path(config, path)?;
+        (vec![path.clone()], vec![path])
+    };
+    let files: Vec<FileInfo> = if paths.len() == 1 && paths[0].is_file() {
+        create_file_info(&paths[0], &config.similarity_config)
+            .into_iter()
+            .collect()
+    } else {
+        file_lister::list_directories(paths, &config.similarity_config)
+            .map_err(|err| err.to_string())?
+    };
+
+    let details = if with_details {
+        files.iter().cloned().map(created_event).collect()
+    } else {
+        Vec::new()
+    };
+    Ok(Outcome {
+        files: details,
+        file_count: files.len() as u64,
+        listed: Some((scanned, files)),
+    })
+}
+
+fn add_watch_root(
+    path: &str,
+    config: &Configuration,
+    config_handle: &ConfigHandle,
+) -> Result<Outcome, String> {
+    if !config.commands_config.allow_watch_root_changes {
+        return Err("The agent does not let the Hub change the watched directories".to_owned());
+    }
+    let root = Path::new(path)
+        .canonicalize()
+        .map_err(|err| format!("{path}: {err}"))?;
+    let allowed = config
+        .commands_config
+        .watch_root_parents
+        .iter()
+        .filter_map(|parent| parent.canonicalize().ok())
+        .any(|parent| root.starts_with(parent));
+    if !allowed {
+        return Err(format!(
+            "{} is not below commands_config.watch_root_parents",
+            root.display()
+        ));
+    }
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+    if watched_roots(config).contains(&root) {
+        return Err(format!("{} is already watched", root.display()));
+    }
+    // The run loop applies it like any other configuration change
+    config_handle.add_watch_root(root)?;
+    Ok(Outcome::default())
+}
+
+fn remove_watch_root(
+    path: &str,
+    config: &Configuration,
+    config_handle: &ConfigHandle,
+) -> Result<Outcome, String> {
+    if !config.commands_config.allow_watch_root_changes {
+        return Err("The agent does not let the Hub change the watched directories".to_owned());
+    }
+    let root = Path::new(path);
+    let canonical_root = root.canonicalize().ok();
+    let is_root = |dir: &PathBuf| {
+        dir == root || (canonical_root.is_some() && dir.canonicalize().ok() == canonical_root)
+    };
+    if !config.filesystem_interface_config.dir.iter().any(is_root) {
+        return Err(format!("{path} is not a watched directory"));
+    }
+    config_handle.remove_watch_root(root)?;
+    Ok(Outcome::default())
+}
+
+fn file_details(path: &str, config: &Configuration, index: &FileIndex) -> Result<Outcome, String> {
+    let path = watched_path(config, path)?;
+    let file = index
+        .get(&path)
+        .or_else(|| create_file_info(&path, &config.similarity_config))
+        .ok_or_else(|| format!("{} is not a file", path.display()))?;
+    Ok(Outcome {
+        files: vec![created_event(file)],
+        file_count: 1,
+        ..Default::default()
+    })
+}
+
+// The watcher reports what the action changed like any other change
+fn file_action(
+    action: FileActionCommand,
+    config: &Configuration,
+    config_handle: &ConfigHandle,
+) -> Result<Outcome, String> {
+    if !config.commands_config.allow_file_actions {
+        return Err("The agent does not let the Hub delete or move files".to_owned());
+    }
+    let path = watched_path(config, &action.path)?;
+    ensure_actionable(config, config_handle, &path)?;
+    if !path.is_file() {
+        return Err(format!("{} is not a file", path.display()));
+    }
+
+    match action.action() {
+        FileAction::Delete => fs::remove_file(&path).map_err(|err| err.to_string())?,
+        FileAction::Move => {
+            let destination = action
+                .destination
+                .as_deref()
+                .ok_or("A move needs a destination")?;
+            let destination = new_watched_path(config, destination)?;
+            ensure_actionable(config, config_handle, &destination)?;
+            if destination.exists() {
+                return Err(format!("{} already exists", destination.display()));
+            }
+            fs::rename(&path, &destination).map_err(|err| err.to_string())?;
+        }
+        FileAction::UnknownAction => return Err("Unknown file action".to_owned()),
+    }
+    Ok(Outcome {
+        file_count: 1,
+        ..Default::default()
+    })
+}
+
+// Commands only reach what is below the watched directories
+fn ensure_watched(config: &Configuration, path: &Path) -> Result<(), String> {
+    if watched_roots(config)
+        .iter()
+        .any(|root| path.starts_with(root))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is not below a watched directory",
+            path.display()
+        ))
+    }
+}
+
+// Files below the directories the Hub added are only for it to act on when the configuration says so
+fn ensure_actionable(
+    config: &Configuration,
+    config_handle: &ConfigHandle,
+    path: &Path,
+) -> Result<(), String> {
+    if config.commands_config.allow_file_actions_on_added_roots
+        || watched_roots(config)
+            .iter()
+            .any(|root| !config_handle.added_by_hub(root) && path.starts_with(root))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "{} is only below watched directories added by the Hub",
+            path.display()
+        ))
+    }
+}
+
+fn watched_path(config: &Configuration, path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path)
+        .canonicalize()
+        .map_err(|err| format!("{path}: {err}"))?;
+    ensure_watched(config, &path)?;
+    Ok(path)
+}
+
+// For paths that don't exist yet, only their directory is resolved
+fn new_watched_path(config: &Configuration, path: &str) -> Result<PathBuf, String> {
+    let path = Path::new(path);
+    let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+        return Err(format!("{} is not a file path", path.display()));
+    };
+    let path = parent
+        .canonicalize()
+        .map_err(|err| format!("{}: {}", parent.display(), err))?
+        .join(name);
+    ensure_watched(config, &path)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_stay_below_the_watched_directories() {
+        let mut config = Configuration::default();
+        config.filesystem_interface_config.dir = vec![PathBuf::from("tests/assets/test_folder")];
+
+        assert!(watched_path(&config, "tests/assets/test_folder/.gitkeep").is_ok());
+        assert!(watched_path(&config, "tests/assets/test_folder/../../../Cargo.toml").is_err());
+        assert!(new_watched_path(&config, "tests/assets/test_folder/new-file").is_ok());
+        assert!(new_watched_path(&config, "tests/assets/new-file").is_err());
+    }
+
+    #[test]
+    fn the_configuration_decides_what_the_hub_may_change() {
+        let mut config = Configuration::default();
+        config.filesystem_interface_config.dir = vec![PathBuf::from("tests/assets/test_folder")];
+        let handle = ConfigHandle::detached(config.clone());
+        let delete = FileActionCommand {
+            action: FileAction::Delete as i32,
+            path: "tests/assets/test_folder/.gitkeep".to_owned(),
+            destination: None,
+        };
+
+        assert!(file_action(delete.clone(), &config, &handle).is_err());
+        assert!(add_watch_root("tests", &config, &handle).is_err());
+
+        config.commands_config.allow_watch_root_changes = true;
+        config.commands_config.watch_root_parents = vec![PathBuf::from("tests/assets")];
+        assert!(add_watch_root("tests", &config, &handle).is_err());
+        assert!(add_watch_root("tests/assets", &config, &handle).is_ok());
+
+        config.commands_config.allow_file_actions = true;
+        config.filesystem_interface_config.dir = vec![PathBuf::from("tests/assets")];
+        let path = Path::new("tests/assets/test_folder/.gitkeep")
+            .canonicalize()
+            .unwrap();
+        assert!(ensure_actionable(&config, &handle, &path).is_err());
+        config.commands_config.allow_file_actions_on_added_roots = true;
+        assert!(ensure_actionable(&config, &handle, &path).is_ok());
+    }
+}
@@ -1,60 +1,187 @@
-use config::{Config, File};
+use config::{Config, Environment, File};
 use serde_derive::{Deserialize, Serialize};
 use std::env::var as env_var;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::info;
 
 use crate::error::AgentError;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AgentData {
     pub latest_version: String,
     pub minimal_version: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct FileSystemInterfaceConfig {
     pub dir: Vec<PathBuf>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ServerConfig {
     pub address: String,
     pub log_level: String,
+    #[serde(default)]
+    pub auth: ApiAuthConfig,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Bearer keys of the local API, which only serves local clients when none is set
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct ApiAuthConfig {
+    #[serde(default)]
+    pub read_keys: Vec<String>,
+    #[serde(default)]
+    pub admin_keys: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct TlsConfig {
+    pub ca_cert: Option<PathBuf>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub pinned_sha256: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct GrpcServerConfig {
     pub host: String,
     pub protocol: String,
     pub port: u16,
     pub log_level: String,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Seconds between two heartbeats sent to the Hub, 0 disables them
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+fn default_heartbeat_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct HubConfig {
     pub host: String,
     pub port: String,
     pub protocol: String,
     pub auth_path: String,
     pub disconnect_path: String,
+    pub challenge_path: String,
+    pub token_path: String,
+    /// Attempts of each connection phase before starting over after a backoff, 0 retries forever
     pub connection_attempt_limit: u32,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    pub backoff_jitter: f64,
+    #[serde(default)]
+    pub tls: TlsConfig,
     pub grpc_server: GrpcServerConfig,
 }
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
+/// Fingerprints of text and office documents to find near-duplicates, off by default
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SimilarityConfig {
+    /// Fingerprints of text files and documents
+    #[serde(default)]
+    pub enabled: bool,
+    /// Perceptual hashes of images
+    #[serde(default)]
+    pub images: bool,
+    /// Share of equal fingerprint bits, from 0 to 1, above which files are near-duplicates
+    #[serde(default = "default_similarity_threshold")]
+    pub threshold: f64,
+    /// Differing bits of their perceptual hashes up to which images look alike
+    #[serde(default = "default_image_max_distance")]
+    pub image_max_distance: u32,
+    /// Bigger files are not fingerprinted
+    #[serde(default = "default_similarity_max_file_size")]
+    pub max_file_size: u64,
+}
+
+fn default_similarity_threshold() -> f64 {
+    0.9
+}
+
+fn default_image_max_distance() -> u32 {
+    8
+}
+
+fn default_similarity_max_file_size() -> u64 {
+    16 * 1024 * 1024
+}
+
+impl Default for SimilarityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            images: false,
+            threshold: default_similarity_threshold(),
+            image_max_distance: default_image_max_distance(),
+            max_file_size: default_similarity_max_file_size(),
+        }
+    }
+}
+
+/// What the Hub may change on this machine through its commands, nothing by default
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CommandsConfig {
+    /// Delete and move files below the watched directories of the configuration
+    #[serde(default)]
+    pub allow_file_actions: bool,
+    /// Also below the watched directories the Hub added
+    #[serde(default)]
+    pub allow_file_actions_on_added_roots: bool,
+    #[serde(default)]
+    pub allow_watch_root_changes: bool,
+    /// Directories below which the Hub may add watched directories
+    #[serde(default)]
+    pub watch_root_parents: Vec<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct StateConfig {
+    pub dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Clone, Deserialize, PartialEq)]
 pub struct LoggerConfig {
     pub term_level: String,
     pub file_level: String,
+    /// Where log files are written, `logs` in the state directory by default
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    #[serde(default = "default_rotation")]
+    pub rotation: String,
+    /// Rotated files kept, 0 keeps them all
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+    #[serde(default)]
+    pub json: bool,
+}
+
+fn default_rotation() -> String {
+    String::from("daily")
 }
 
-#[derive(Debug, Deserialize, Clone, Serialize)]
+fn default_max_files() -> usize {
+    7
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
 pub struct Configuration {
     pub agent_data: AgentData,
     pub filesystem_interface_config: FileSystemInterfaceConfig,
     pub server_config: ServerConfig,
     pub logger_config: LoggerConfig,
     pub hub_config: HubConfig,
+    #[serde(default)]
+    pub state_config: StateConfig,
+    #[serde(default)]
+    pub similarity_config: SimilarityConfig,
+    #[serde(default)]
+    pub commands_config: CommandsConfig,
 }
 
 impl Default for Configuration {
@@ -70,6 +197,7 @@ impl Default for Configuration {
             server_config: ServerConfig {
                 address: String::from("0.0.0.0:8111"),
                 log_level: String::from("info"),
+                auth: ApiAuthConfig::default(),
             },
             hub_config: HubConfig {
                 host: String::from("localhost"),
@@ -77,39 +205,163 @@ impl Default for Configuration {
                 protocol: String::from("http"),
                 auth_path: String::from("/gateway/auth/AOTH"),
                 disconnect_path: String::from("/gateway/auth/AOTH/{agent_id}/disconnect"),
-                connection_attempt_limit: 30,
+                challenge_path: String::from("/gateway/auth/AOTH/{agent_id}/challenge"),
+                token_path: String::from("/gateway/auth/AOTH/{agent_id}/token"),
+                connection_attempt_limit: 0,
+                initial_backoff_ms: 1000,
+                max_backoff_ms: 60000,
+                backoff_jitter: 0.2,
+                tls: TlsConfig::default(),
                 grpc_server: GrpcServerConfig {
                     host: String::from("localhost"),
                     protocol: String::from("http"),
                     port: 5057,
                     log_level: String::from("info"),
+                    tls: TlsConfig::default(),
+                    heartbeat_interval_secs: default_heartbeat_interval_secs(),
                 },
             },
             logger_config: LoggerConfig {
                 term_level: String::from("debug"),
                 file_level: String::from("warn"),
+                dir: None,
+                rotation: default_rotation(),
+                max_files: default_max_files(),
+                json: false,
             },
+            state_config: StateConfig::default(),
+            similarity_config: SimilarityConfig::default(),
+            commands_config: CommandsConfig::default(),
         }
     }
 }
 
+const REDACTED: &str = "<redacted>";
+
+// Keys holding lists, given comma separated in the environment
+const ENV_LIST_KEYS: [&str; 5] = [
+    "filesystem_interface_config.dir",
+    "server_config.auth.read_keys",
+    "server_config.auth.admin_keys",
+    "hub_config.tls.pinned_sha256",
+    "hub_config.grpc_server.tls.pinned_sha256",
+];
+
 impl Configuration {
-    pub fn init() -> Result<Self, AgentError> {
+    /// Layers the built-in defaults, the config directory, `config_path` and `TIDY_` variables
+    pub fn init(config_path: Option<&Path>) -> Result<Self, AgentError> {
         let env = env_var("TIDY_ENV").unwrap_or_else(|_| "development".into());
 
         info!("Loading configuration for environment: {}", env);
 
-        let mut config_dir =
-            std::env::current_exe().expect("Failed to find current executable path");
-        config_dir.pop();
-        config_dir.push("config");
+        let mut builder =
+            Config::builder().add_source(Config::try_from(&Configuration::default())?);
+        for file in Self::files(config_path) {
+            let required = Some(file.as_path()) == config_path;
+            if required || file.is_file() {
+                info!("Loading configuration file: {}", file.display());
+            }
+            builder = builder.add_source(File::from(file).required(required));
+        }
+        builder = builder.add_source(environment());
+
+        Ok(builder.build()?.try_deserialize()?)
+    }
 
-        let builder = Config::builder()
-            .add_source(File::with_name("config/default.json").required(false))
-            .add_source(File::with_name(&format!("config/{env}.json")).required(false))
+    /// Copy safe to hand out, with secrets replaced by a placeholder
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        let auth = &mut config.server_config.auth;
+        for key in auth.read_keys.iter_mut().chain(auth.admin_keys.iter_mut()) {
+            *key = String::from(REDACTED);
+        }
+        config
+    }
+
+    /// Files the configuration is read from by `init`, by increasing priority, existing or not
+    pub fn files(config_path: Option<&Path>) -> Vec<PathBuf> {
+        let env = env_var("TIDY_ENV").unwrap_or_else(|_| "development".into());
+        let mut files = match config_dirs().into_iter().find(|dir| dir.is_dir()) {
+            Some(config_dir) => vec![
+                config_dir.join("default.json"),
+                config_dir.join(format!("{env}.json")),
+            ],
+            None => vec![],
+        };
+        files.extend(config_path.map(Path::to_path_buf));
+        files
+    }
+}
+
+// `TIDY_HUB_CONFIG__GRPC_SERVER__PORT` overrides `hub_config.grpc_server.port`
+fn environment() -> Environment {
+    ENV_LIST_KEYS.iter().fold(
+        Environment::with_prefix("TIDY")
+            .prefix_separator("_")
+            .separator("__")
+            .try_parsing(true)
+            .list_separator(","),
+        |environment, key| environment.with_list_parse_key(key),
+    )
+}
+
+// The working directory first, then next to the executable, then the system wide one
+fn config_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("config")];
+    if let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        dirs.push(exe_dir.join("config"));
+    }
+    dirs.push(system_config_dir());
+    dirs
+}
+
+#[cfg(windows)]
+fn system_config_dir() -> PathBuf {
+    let program_data = env_var("ProgramData").unwrap_or_else(|_| r"C:\ProgramData".into());
+    PathBuf::from(program_data)
+        .join("TidyBee")
+        .join("tidybee-agent")
+        .join("config")
+}
+
+#[cfg(not(windows))]
+fn system_config_dir() -> PathBuf {
+    PathBuf::from("/etc/tidybee-agent")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_overrides_nested_keys_and_lists() {
+        let variables = [
+            ("TIDY_HUB_CONFIG__GRPC_SERVER__PORT", "6000"),
+            ("TIDY_FILESYSTEM_INTERFACE_CONFIG__DIR", "/srv/a,/srv/b"),
+            ("TIDY_ENV", "docker"),
+        ];
+        let config: Configuration = Config::builder()
+            .add_source(Config::try_from(&Configuration::default()).unwrap())
+            .add_source(
+                environment().source(Some(
+                    variables
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                )),
+            )
             .build()
+            .unwrap()
+            .try_deserialize()
             .unwrap();
-        let config: Configuration = builder.try_deserialize().unwrap();
-        Ok(config)
+
+        assert_eq!(config.hub_config.grpc_server.port, 6000);
+        assert_eq!(
+            config.filesystem_interface_config.dir,
+            vec![PathBuf::from("/srv/a"), PathBuf::from("/srv/b")]
+        );
     }
 }
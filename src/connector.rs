@@ -0,0 +1,115 @@
+use crate::error::HubError;
+use crate::http::connection::{HubConnectionStatus, SharedHubConnectionStatus};
+use crate::http::hub::Hub;
+use anyhow::Error;
+use std::future;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, Instant};
+use tracing::{error, warn};
+
+pub enum Connection {
+    /// The client is connected, it replaces the current one
+    Connected(Box<Hub>),
+    /// Another Hub could not be reached, the current client stays
+    SwitchFailed,
+}
+
+/// Connects to the Hub in the background, trying again with the backoff of the client, so that the agent keeps running meanwhile
+#[derive(Default)]
+pub struct HubConnector {
+    connecting: Option<JoinHandle<(Hub, Result<String, Error>)>>,
+    // The client to connect again once the delay is over
+    waiting: Option<(Hub, Instant)>,
+    // While switching Hubs, the status of the current client to go back to
+    switching: Option<(SharedHubConnectionStatus, HubConnectionStatus)>,
+}
+
+impl HubConnector {
+    /// Connects `hub` once, in place of the connected client, which is kept when it fails
+    pub fn switch(&mut self, hub: Hub) {
+        let status = hub.status();
+        let switching = self.switching.take().unwrap_or_else(|| {
+            let previous = status.lock().unwrap().clone();
+            (status, previous)
+        });
+        self.connect(hub);
+        self.switching = Some(switching);
+    }
+
+    /// Connects `hub`, in place of the client being connected if any
+    pub fn connect(&mut self, mut hub: Hub) {
+        self.cancel();
+        self.connecting = Some(tokio::spawn(async move {
+            let connected = hub.connect().await;
+            (hub, connected)
+        }));
+    }
+
+    pub fn cancel(&mut self) {
+        if let Some(connecting) = self.connecting.take() {
+            connecting.abort();
+        }
+        self.waiting = None;
+        if let Some((status, previous)) = self.switching.take() {
+            *status.lock().unwrap() = previous;
+        }
+    }
+
+    /// Waits for a client to connect or a switch to fail, never returning when there is nothing to connect
+    pub async fn next(&mut self) -> Connection {
+        loop {
+            if let Some(connecting) = &mut self.connecting {
+                let joined = connecting.await;
+                self.connecting = None;
+                match joined {
+                    Ok((hub, Ok(_))) => {
+                        self.switching = None;
+                        return Connection::Connected(Box::new(hub));
+                    }
+                    Ok((_, Err(err))) if self.switching.is_some() => {
+                        error!("Could not connect to the new Hub: {}", err);
+                        self.cancel();
+                        return Connection::SwitchFailed;
+                    }
+                    Ok((hub, Err(err))) if !gave_up(&err) => {
+                        error!("Stopped connecting to the Hub: {}", err);
+                        hub.status().lock().unwrap().failure(&err, None);
+                    }
+                    Ok((mut hub, Err(err))) => {
+                        let delay = hub.retry_delay();
+                        warn!(
+                            "Could not connect to the Hub: {}, trying again in {:?}",
+                            err, delay
+                        );
+                        hub.status().lock().unwrap().failure(&err, Some(delay));
+                        self.waiting = Some((hub, Instant::now() + delay));
+                    }
+                    Err(err) => {
+                        error!("Stopped connecting to the Hub: {}", err);
+                        if self.switching.is_some() {
+                            self.cancel();
+                            return Connection::SwitchFailed;
+                        }
+                    }
+                }
+            }
+            match &self.waiting {
+                Some((_, retry_at)) => {
+                    sleep_until(*retry_at).await;
+                    if let Some((hub, _)) = self.waiting.take() {
+                        self.connect(hub);
+                    }
+                }
+                None => return future::pending().await,
+            }
+        }
+    }
+}
+
+// Hub::connect retries on its own until the attempts run out, anything else it returns, such as a refused registration, is there to stay
+fn gave_up(err: &Error) -> bool {
+    matches!(
+        err.downcast_ref::<HubError>(),
+        Some(HubError::MaximumAttemptsReached())
+    )
+}
@@ -4,22 +4,36 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum AgentError {
-    #[error(transparent)]
+    #[error("Invalid configuration: {0}")]
     InvalidConfig(#[from] config_error),
     #[error(transparent)]
     Io(#[from] io_error),
+    #[error("The configuration has {0} error(s)")]
+    ConfigurationRejected(usize),
     #[error("Path entry isn't a directory")]
     NotADirectory(),
+    #[error("Invalid agent id stored in {0}")]
+    InvalidAgentId(std::path::PathBuf),
+    #[error("Could not reach the agent at {0}: {1}")]
+    AgentUnreachable(String, String),
+    #[error("Could not create the Hub client: {0}")]
+    HubClientCreationFailed(String),
+    #[error("Could not connect to the Hub: {0}")]
+    HubConnectionFailed(String),
+    #[error("The local API could not listen on {0}: {1}")]
+    ServerFailed(String, String),
 }
 
 #[derive(Error, Debug)]
 pub enum HubError {
-    #[error("Unexpected error from the Hub: {0}")]
-    UnExpectedError(String),
     #[error("Hub client creation failed: {0}")]
     HubClientCreationFailed(String),
     #[error(transparent)]
     HttpError(#[from] reqwest::Error),
+    #[error("The Hub answered with status {0}")]
+    UnexpectedStatus(reqwest::StatusCode),
+    #[error("Invalid TLS configuration: {0}")]
+    TlsConfigError(String),
     #[error("Maximum number of attemps to connect to the Hub reached without success")]
     MaximumAttemptsReached(),
     #[error(transparent)]
@@ -30,12 +44,18 @@ pub enum HubError {
 pub enum GrpcClientError {
     #[error(transparent)]
     InvalidEndpoint(#[from] tonic::transport::Error),
-    #[error("Agent UUID not set")]
-    AgentUuidNotSet(),
+    #[error("Token store not set")]
+    TokenStoreNotSet(),
     #[error("gRPC client is not connected")]
     ClientNotConnected(),
     #[error("Error creating file info")]
     FileInfoError(),
     #[error("Error sending event to Hub")]
     EventSendError(),
+    #[error("The Hub rejected {0} event(s)")]
+    EventsRejected(usize),
+    #[error("Too many events wait for the Hub, dropping new ones")]
+    OutboxFull(),
+    #[error("Heartbeat failed: {0}")]
+    HeartbeatError(String),
 }
@@ -0,0 +1,133 @@
+use futures::stream::{self, Stream};
+use notify::event::ModifyKind;
+use notify_debouncer_full::DebouncedEvent;
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+/// Events kept for subscribers that fall behind before the oldest ones are dropped
+pub const EVENT_BUFFER: usize = 1024;
+
+pub type EventSender = broadcast::Sender<FileEvent>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileEventKind {
+    Created,
+    Modified,
+    Renamed,
+    Removed,
+}
+
+/// A watcher event as shown to the local API clients
+#[derive(Debug, Clone, Serialize)]
+pub struct FileEvent {
+    pub kind: FileEventKind,
+    pub paths: Vec<PathBuf>,
+    pub root: Option<PathBuf>,
+    pub time: String,
+}
+
+impl FileEvent {
+    /// Returns `None` for the events the agent ignores, such as accesses
+    pub fn from_debounced(event: &DebouncedEvent, roots: &[PathBuf]) -> Option<Self> {
+        let kind = match event.kind {
+            notify::EventKind::Create(_) => FileEventKind::Created,
+            notify::EventKind::Modify(ModifyKind::Name(_)) => FileEventKind::Renamed,
+            notify::EventKind::Modify(_) => FileEventKind::Modified,
+            notify::EventKind::Remove(_) => FileEventKind::Removed,
+            _ => return None,
+        };
+        let root = event
+            .paths
+            .first()
+            .and_then(|path| roots.iter().find(|root| path.starts_with(root)))
+            .cloned();
+        Some(FileEvent {
+            kind,
+            paths: event.paths.clone(),
+            root,
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+        })
+    }
+}
+
+/// Criteria an event must all meet, unset ones match every event
+#[derive(Debug, Default, Clone)]
+pub struct EventFilter {
+    pub root: Option<PathBuf>,
+    pub kinds: Vec<FileEventKind>,
+    pub prefix: Option<PathBuf>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, event: &FileEvent) -> bool {
+        let checks = [
+            self.root
+                .as_ref()
+                .map(|root| event.root.as_ref() == Some(root)),
+            (!self.kinds.is_empty()).then(|| self.kinds.contains(&event.kind)),
+            self.prefix
+                .as_ref()
+                .map(|prefix| event.paths.iter().any(|path| path.starts_with(prefix))),
+        ];
+        checks.into_iter().all(|check| check.unwrap_or(true))
+    }
+}
+
+/// The events published after subscribing that match `filter`, until the agent stops
+pub fn subscribe(sender: &EventSender, filter: EventFilter) -> impl Stream<Item = FileEvent> {
+    stream::unfold(
+        (sender.subscribe(), filter),
+        |(mut events, filter)| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) if filter.matches(&event) => return Some((event, (events, filter))),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(
+                            "An event subscriber fell behind, {} events were dropped",
+                            skipped
+                        )
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_by_root_kind_and_prefix() {
+        let event = FileEvent {
+            kind: FileEventKind::Modified,
+            paths: vec![PathBuf::from("/data/photos/holiday.jpg")],
+            root: Some(PathBuf::from("/data")),
+            time: String::new(),
+        };
+        assert!(EventFilter::default().matches(&event));
+
+        let filter = EventFilter {
+            root: Some(PathBuf::from("/data")),
+            kinds: vec![FileEventKind::Created, FileEventKind::Modified],
+            prefix: Some(PathBuf::from("/data/photos")),
+        };
+        assert!(filter.matches(&event));
+        assert!(!EventFilter {
+            kinds: vec![FileEventKind::Removed],
+            ..filter.clone()
+        }
+        .matches(&event));
+        assert!(!EventFilter {
+            prefix: Some(PathBuf::from("/data/music")),
+            ..filter
+        }
+        .matches(&event));
+    }
+}
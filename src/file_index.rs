@@ -0,0 +1,434 @@
+use crate::configuration::SimilarityConfig;
+use crate::file_info::{create_file_info, FileInfo};
+use crate::file_lister;
+use crate::file_type::FileCategory;
+use notify::event::{EventKind, ModifyKind};
+use notify_debouncer_full::DebouncedEvent;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tracing::warn;
+
+/// Files with the same content, every copy beyond the first one wasting space
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct DuplicateCluster {
+    pub hash: String,
+    pub size: u64,
+    pub wasted_bytes: u64,
+    pub files: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct Files {
+    by_path: BTreeMap<PathBuf, FileInfo>,
+    // Only files of the same size can have the same content
+    by_size: HashMap<u64, BTreeSet<PathBuf>>,
+}
+
+impl Files {
+    fn insert(&mut self, file: FileInfo) {
+        let (path, size) = (file.path.clone(), file.size);
+        if let Some(previous) = self.by_path.insert(path.clone(), file) {
+            self.forget_size(previous.size, &previous.path);
+        }
+        self.by_size.entry(size).or_default().insert(path);
+    }
+
+    fn retain(&mut self, keep: impl Fn(&Path) -> bool) {
+        let mut removed = Vec::new();
+        self.by_path.retain(|path, file| {
+            let kept = keep(path);
+            if !kept {
+                removed.push((file.size, path.clone()));
+            }
+            kept
+        });
+        for (size, path) in removed {
+            self.forget_size(size, &path);
+        }
+    }
+
+    // Below a path, the files come right after it in the BTreeMap
+    fn remove_below(&mut self, path: &Path) {
+        let removed: Vec<(PathBuf, u64)> = self
+            .by_path
+            .range(path.to_path_buf()..)
+            .take_while(|(file_path, _)| file_path.starts_with(path))
+            .map(|(file_path, file)| (file_path.clone(), file.size))
+            .collect();
+        for (file_path, size) in removed {
+            self.by_path.remove(&file_path);
+            self.forget_size(size, &file_path);
+        }
+    }
+
+    fn forget_size(&mut self, size: u64, path: &Path) {
+        if let Some(paths) = self.by_size.get_mut(&size) {
+            paths.remove(path);
+            if paths.is_empty() {
+                self.by_size.remove(&size);
+            }
+        }
+    }
+}
+
+/// Files of the watched directories by canonical path, kept up to date from the watcher events
+#[derive(Clone, Default)]
+pub struct FileIndex {
+    files: Arc<RwLock<Files>>,
+}
+
+impl FileIndex {
+    pub fn insert(&self, files: Vec<FileInfo>) {
+        let mut index = self.files.write().unwrap();
+        for file in files {
+            index.insert(file);
+        }
+    }
+
+    /// Replaces everything below `roots` with `files`, dropping the files that are gone
+    pub fn replace(&self, roots: &[PathBuf], files: Vec<FileInfo>) {
+        let mut index = self.files.write().unwrap();
+        index.retain(|path| !roots.iter().any(|root| path.starts_with(root)));
+        for file in files {
+            index.insert(file);
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<FileInfo> {
+        self.files.read().unwrap().by_path.get(path).cloned()
+    }
+
+    /// Number and total size of the files below `root`
+    pub fn totals(&self, root: &Path) -> (u64, u64) {
+        self.files
+            .read()
+            .unwrap()
+            .by_path
+            .range(root.to_path_buf()..)
+            .take_while(|(path, _)| path.starts_with(root))
+            .fold((0, 0), |(files, bytes), (_, file)| {
+                (files + 1, bytes + file.size)
+            })
+    }
+
+    /// Drops a file, or a directory and everything below it
+    pub fn remove(&self, path: &Path) {
+        self.files.write().unwrap().remove_below(path);
+    }
+
+    /// Drops the files that are not below one of `roots` anymore
+    pub fn retain_roots(&self, roots: &[PathBuf]) {
+        self.files
+            .write()
+            .unwrap()
+            .retain(|file_path| roots.iter().any(|root| file_path.starts_with(root)));
+    }
+
+    pub fn filter(&self, filter: &FileFilter) -> Vec<FileInfo> {
+        self.files
+            .read()
+            .unwrap()
+            .by_path
+            .values()
+            .filter(|file| filter.matches(file))
+            .cloned()
+            .collect()
+    }
+
+    /// Paths, sizes and similarity fingerprints of the fingerprinted files
+    pub fn fingerprints(&self) -> Vec<(PathBuf, u64, u64)> {
+        self.hashes(|file| file.fingerprint.as_deref())
+    }
+
+    /// Paths, sizes and perceptual hashes of the images
+    pub fn image_hashes(&self) -> Vec<(PathBuf, u64, u64)> {
+        self.hashes(|file| file.image_hash.as_deref())
+    }
+
+    fn hashes(&self, hash: impl Fn(&FileInfo) -> Option<&str>) -> Vec<(PathBuf, u64, u64)> {
+        self.files
+            .read()
+            .unwrap()
+            .by_path
+            .values()
+            .filter_map(|file| {
+                let hash = u64::from_str_radix(hash(file)?, 16).ok()?;
+                Some((file.path.clone(), file.size, hash))
+            })
+            .collect()
+    }
+
+    /// Groups the non-empty files of at least `min_size` bytes by content, the most wasteful first
+    pub fn duplicates(&self, min_size: u64) -> Vec<DuplicateCluster> {
+        let files = self.files.read().unwrap();
+        let mut clusters = Vec::new();
+        for (size, paths) in &files.by_size {
+            if paths.len() < 2 || *size == 0 || *size < min_size {
+                continue;
+            }
+            let mut by_hash: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+            for path in paths {
+                if let Some(hash) = files.by_path[path].hash.as_deref() {
+                    by_hash.entry(hash).or_default().push(path.clone());
+                }
+            }
+            clusters.extend(
+                by_hash
+                    .into_iter()
+                    .filter(|(_, paths)| paths.len() > 1)
+                    .map(|(hash, paths)| DuplicateCluster {
+                        hash: hash.to_owned(),
+                        size: *size,
+                        wasted_bytes: size * (paths.len() as u64 - 1),
+                        files: paths,
+                    }),
+            );
+        }
+        clusters.sort_by(|a, b| {
+            b.wasted_bytes
+                .cmp(&a.wasted_bytes)
+                .then_with(|| a.files.cmp(&b.files))
+        });
+        clusters
+    }
+
+    /// Updates the paths of a watcher event from what they hold on the disk
+    pub fn apply(&self, event: &ReadEvent) {
+        for (path, on_disk) in event.event.paths.iter().zip(&event.paths) {
+            match on_disk {
+                OnDisk::File(file) => self.insert(vec![file.clone()]),
+                OnDisk::Tree(files) => self.insert(files.clone()),
+                OnDisk::Directory => {}
+                OnDisk::Gone => self.remove(path),
+            }
+        }
+    }
+}
+
+/// What a path of a watcher event holds on the disk
+pub enum OnDisk {
+    File(FileInfo),
+    /// A directory that just appeared, with the files below it
+    Tree(Vec<FileInfo>),
+    Directory,
+    Gone,
+}
+
+impl OnDisk {
+    fn read(path: &Path, kind: &EventKind, similarity: &SimilarityConfig) -> Self {
+        if !path.is_dir() {
+            return create_file_info(&path.to_path_buf(), similarity)
+                .map_or(Self::Gone, Self::File);
+        }
+        // Listing a whole tree again is only worth it when it just appeared
+        if !matches!(
+            kind,
+            EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(_))
+        ) {
+            return Self::Directory;
+        }
+        match file_lister::list_directories(vec![path.to_path_buf()], similarity) {
+            Ok(files) => Self::Tree(files),
+            Err(err) => {
+                warn!("Could not list {}: {}", path.display(), err);
+                Self::Directory
+            }
+        }
+    }
+}
+
+/// A watcher event and what its paths hold, read once for the index and the Hub
+pub struct ReadEvent {
+    pub event: DebouncedEvent,
+    pub paths: Vec<OnDisk>,
+}
+
+impl ReadEvent {
+    /// Hashes what the event touched, which can take a while, so it belongs off the async workers
+    pub fn read(event: DebouncedEvent, similarity: &SimilarityConfig) -> Self {
+        let paths = if event.kind.is_access() {
+            Vec::new()
+        } else {
+            event
+                .paths
+                .iter()
+                .map(|path| OnDisk::read(path, &event.kind, similarity))
+                .collect()
+        };
+        Self { event, paths }
+    }
+
+    /// What the first path of the event holds
+    pub fn first(&self) -> &OnDisk {
+        self.paths.first().unwrap_or(&OnDisk::Gone)
+    }
+}
+
+/// Criteria a file must all meet, unset ones match every file
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    pub root: Option<PathBuf>,
+    pub name: Option<String>,
+    pub extension: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<SystemTime>,
+    pub modified_before: Option<SystemTime>,
+    pub hash: Option<String>,
+    pub category: Option<FileCategory>,
+    pub type_mismatch: Option<bool>,
+}
+
+impl FileFilter {
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        let name = file
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let extension = file
+            .path
+            .extension()
+            .map(|extension| extension.to_string_lossy());
+
+        let checks = [
+            self.root.as_ref().map(|root| file.path.starts_with(root)),
+            self.name.as_ref().map(|pattern| glob_match(pattern, &name)),
+            self.extension.as_ref().map(|wanted| {
+                extension.as_ref().is_some_and(|extension| {
+                    extension.eq_ignore_ascii_case(wanted.trim_start_matches('.'))
+                })
+            }),
+            self.min_size.map(|min_size| file.size >= min_size),
+            self.max_size.map(|max_size| file.size <= max_size),
+            self.modified_after.map(|after| file.last_modified >= after),
+            self.modified_before
+                .map(|before| file.last_modified <= before),
+            self.hash
+                .as_ref()
+                .map(|hash| file.hash.as_ref() == Some(hash)),
+            self.category.map(|category| file.category == category),
+            self.type_mismatch
+                .map(|type_mismatch| file.type_mismatch == type_mismatch),
+        ];
+        checks.into_iter().all(|check| check.unwrap_or(true))
+    }
+}
+
+/// Matches `*` against any run of characters and `?` against a single one
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume after the last `*` when the rest does not match
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    n = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*.txt", "notes.txt"));
+        assert!(glob_match("report-??.pdf", "report-01.pdf"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(!glob_match("*.txt", "notes.txt.bak"));
+        assert!(!glob_match("report-?.pdf", "report-01.pdf"));
+    }
+
+    #[test]
+    fn filter_combines_criteria() {
+        let file = FileInfo {
+            path: PathBuf::from("/data/photos/holiday.JPG"),
+            size: 2048,
+            hash: Some("42".to_owned()),
+            ..Default::default()
+        };
+        let filter = FileFilter {
+            root: Some(PathBuf::from("/data")),
+            extension: Some(".jpg".to_owned()),
+            min_size: Some(1024),
+            hash: Some("42".to_owned()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&file));
+
+        let too_small = FileFilter {
+            min_size: Some(4096),
+            ..filter
+        };
+        assert!(!too_small.matches(&file));
+    }
+
+    #[test]
+    fn duplicates_follow_the_index() {
+        let file = |path: &str, size: u64, hash: &str| FileInfo {
+            path: PathBuf::from(path),
+            size,
+            hash: Some(hash.to_owned()),
+            ..Default::default()
+        };
+        let index = FileIndex::default();
+        index.insert(vec![
+            file("/a/one", 100, "x"),
+            file("/b/one", 100, "x"),
+            file("/b/two", 100, "y"),
+            file("/a/big", 5000, "z"),
+            file("/b/big", 5000, "z"),
+            file("/b/big-copy", 5000, "z"),
+            file("/a/empty", 0, "e"),
+            file("/b/empty", 0, "e"),
+        ]);
+
+        let clusters = index.duplicates(0);
+        assert_eq!(clusters.len(), 2);
+        assert_eq!(clusters[0].hash, "z");
+        assert_eq!(clusters[0].wasted_bytes, 10000);
+        assert_eq!(clusters[1].files.len(), 2);
+
+        index.insert(vec![file("/b/one", 120, "w")]);
+        index.remove(Path::new("/b/big-copy"));
+        let clusters = index.duplicates(0);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].wasted_bytes, 5000);
+        assert!(index.duplicates(6000).is_empty());
+
+        index.replace(&[PathBuf::from("/b")], vec![file("/b/three", 100, "x")]);
+        let clusters = index.duplicates(0);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].files.len(), 2);
+        assert!(index.get(Path::new("/b/big")).is_none());
+        assert!(index.get(Path::new("/a/big")).is_some());
+
+        index.remove(Path::new("/a"));
+        assert!(index.get(Path::new("/a/big")).is_none());
+        assert!(index.duplicates(0).is_empty());
+        assert!(index.get(Path::new("/b/three")).is_some());
+    }
+}
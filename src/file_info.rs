@@ -1,9 +1,13 @@
+use crate::configuration::SimilarityConfig;
+use crate::file_type::{self, FileCategory};
+use crate::similarity;
+use crate::telemetry;
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
-    io::Read,
+    io::{self, Read},
     path::{Path, PathBuf},
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 use tracing::warn;
 use xxhash_rust::xxh3::xxh3_128;
@@ -14,6 +18,19 @@ pub struct FileInfo {
     pub path: PathBuf,
     pub size: u64,
     pub hash: Option<String>,
+    /// Similarity fingerprint of text and office documents, in hexadecimal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Perceptual hash of images, in hexadecimal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default)]
+    pub category: FileCategory,
+    /// The contents are of another kind than the extension says
+    #[serde(default)]
+    pub type_mismatch: bool,
     pub last_modified: SystemTime,
     pub last_accessed: SystemTime,
 }
@@ -25,6 +42,11 @@ impl Default for FileInfo {
             path: PathBuf::new(),
             size: 0,
             hash: None,
+            fingerprint: None,
+            image_hash: None,
+            mime_type: None,
+            category: FileCategory::Other,
+            type_mismatch: false,
             last_modified: SystemTime::UNIX_EPOCH,
             last_accessed: SystemTime::UNIX_EPOCH,
         }
@@ -53,14 +75,17 @@ pub fn fix_canonicalize_path<P: AsRef<Path>>(path: P) -> PathBuf {
     }
 }
 
-pub fn get_file_signature(path: &PathBuf) -> u128 {
-    let mut file = fs::File::open(path).unwrap();
+pub fn get_file_signature(path: &PathBuf) -> io::Result<u128> {
+    let start = Instant::now();
+    let mut file = fs::File::open(path)?;
     let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).unwrap();
-    xxh3_128(&buffer)
+    file.read_to_end(&mut buffer)?;
+    let signature = xxh3_128(&buffer);
+    telemetry::file_hashed(buffer.len() as u64, start.elapsed());
+    Ok(signature)
 }
 
-pub fn create_file_info(path: &PathBuf) -> Option<FileInfo> {
+pub fn create_file_info(path: &PathBuf, similarity: &SimilarityConfig) -> Option<FileInfo> {
     if path.is_dir() {
         return None;
     }
@@ -70,16 +95,35 @@ pub fn create_file_info(path: &PathBuf) -> Option<FileInfo> {
             let size: u64 = md.len();
             let last_modified: SystemTime = md.modified().ok()?;
             let last_accessed: SystemTime = md.accessed().ok()?;
-            let file_signature = get_file_signature(path);
+            let file_signature = match get_file_signature(path) {
+                Ok(file_signature) => file_signature,
+                Err(err) => {
+                    warn!("Could not hash {:?}: {}", path, err);
+                    return None;
+                }
+            };
+            let file_type = file_type::detect(path)
+                .map_err(|err| warn!("Could not detect the type of {:?}: {}", path, err))
+                .ok();
 
             Some(FileInfo {
                 pretty_path: fix_canonicalize_path(fs::canonicalize(path).unwrap()),
                 path: fix_canonicalize_path(fs::canonicalize(path).unwrap()),
                 size,
                 hash: Some(file_signature.to_string()),
+                fingerprint: similarity::fingerprint(path, size, similarity)
+                    .map(|fingerprint| format!("{:016x}", fingerprint)),
+                image_hash: similarity::image_hash(path, size, similarity)
+                    .map(|image_hash| format!("{:016x}", image_hash)),
+                mime_type: file_type
+                    .as_ref()
+                    .map(|file_type| file_type.mime_type.clone()),
+                category: file_type
+                    .as_ref()
+                    .map_or(FileCategory::Other, |file_type| file_type.category),
+                type_mismatch: file_type.is_some_and(|file_type| file_type.mismatch),
                 last_modified,
                 last_accessed,
-                ..Default::default()
             })
         }
         Err(err) => {
@@ -1,12 +1,142 @@
+use serde_derive::Serialize;
 use std::fs::read_dir;
 use std::fs::DirEntry;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 use tracing::info;
 
+use crate::configuration::SimilarityConfig;
 use crate::error::AgentError;
 use crate::file_info::{create_file_info, FileInfo};
+use crate::telemetry;
 
-pub fn list_directories(directories: Vec<PathBuf>) -> Result<Vec<FileInfo>, AgentError> {
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanState {
+    #[default]
+    Pending,
+    Counting,
+    Hashing,
+    Complete,
+    Failed,
+}
+
+/// Progress of a scan of the watched directories, shared with the local API
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct ScanProgress {
+    pub state: ScanState,
+    pub total_files: u64,
+    pub total_bytes: u64,
+    pub scanned_files: u64,
+    pub scanned_bytes: u64,
+    pub started_at: Option<SystemTime>,
+    pub finished_at: Option<SystemTime>,
+    pub eta_secs: Option<u64>,
+}
+
+pub type SharedScanProgress = Arc<Mutex<ScanProgress>>;
+
+impl ScanProgress {
+    fn file_scanned(&mut self, file: &FileInfo, elapsed: f64) {
+        self.scanned_files += 1;
+        self.scanned_bytes += file.size;
+        // Hashing time grows with the size, so the bytes left tell how long it will take
+        self.eta_secs = (self.scanned_bytes > 0).then(|| {
+            let left = self.total_bytes.saturating_sub(self.scanned_bytes) as f64;
+            (elapsed * left / self.scanned_bytes as f64) as u64
+        });
+    }
+}
+
+pub fn list_directories(
+    directories: Vec<PathBuf>,
+    similarity: &SimilarityConfig,
+) -> Result<Vec<FileInfo>, AgentError> {
+    list_with(directories, similarity, &mut |_| {})
+}
+
+/// Lists the directories like `list_directories`, counting them first to report the progress
+pub fn scan_directories(
+    directories: Vec<PathBuf>,
+    similarity: &SimilarityConfig,
+    progress: &SharedScanProgress,
+) -> Result<Vec<FileInfo>, AgentError> {
+    *progress.lock().unwrap() = ScanProgress {
+        state: ScanState::Counting,
+        started_at: Some(SystemTime::now()),
+        ..Default::default()
+    };
+    let (total_files, total_bytes) = directories
+        .iter()
+        .map(|directory| count_files(directory))
+        .fold((0, 0), |(files, bytes), (dir_files, dir_bytes)| {
+            (files + dir_files, bytes + dir_bytes)
+        });
+    {
+        let mut progress = progress.lock().unwrap();
+        progress.state = ScanState::Hashing;
+        progress.total_files = total_files;
+        progress.total_bytes = total_bytes;
+    }
+
+    let start = Instant::now();
+    let result = list_with(directories, similarity, &mut |file| {
+        progress
+            .lock()
+            .unwrap()
+            .file_scanned(file, start.elapsed().as_secs_f64());
+    });
+
+    let mut progress = progress.lock().unwrap();
+    progress.state = if result.is_ok() {
+        ScanState::Complete
+    } else {
+        ScanState::Failed
+    };
+    progress.finished_at = Some(SystemTime::now());
+    progress.eta_secs = None;
+    result
+}
+
+fn list_with(
+    directories: Vec<PathBuf>,
+    similarity: &SimilarityConfig,
+    on_file: &mut dyn FnMut(&FileInfo),
+) -> Result<Vec<FileInfo>, AgentError> {
+    let start = Instant::now();
+    let files = list_recursively(directories, similarity, on_file)?;
+    let bytes = files.iter().map(|file| file.size).sum();
+    telemetry::scan_completed(files.len(), bytes, start.elapsed());
+    Ok(files)
+}
+
+// Counts without hashing, what can't be read is left out of the estimate
+fn count_files(directory: &Path) -> (u64, u64) {
+    let Ok(entries) = read_dir(directory) else {
+        return (0, 0);
+    };
+    entries
+        .filter_map(Result::ok)
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                count_files(&path)
+            } else {
+                path.metadata()
+                    .map_or((0, 0), |metadata| (1, metadata.len()))
+            }
+        })
+        .fold((0, 0), |(files, bytes), (entry_files, entry_bytes)| {
+            (files + entry_files, bytes + entry_bytes)
+        })
+}
+
+fn list_recursively(
+    directories: Vec<PathBuf>,
+    similarity: &SimilarityConfig,
+    on_file: &mut dyn FnMut(&FileInfo),
+) -> Result<Vec<FileInfo>, AgentError> {
     let mut file_info_vec: Vec<FileInfo> = Vec::new();
 
     for directory in directories {
@@ -16,10 +146,11 @@ pub fn list_directories(directories: Vec<PathBuf>) -> Result<Vec<FileInfo>, Agen
                 let dir_path: PathBuf = dir_entry.path();
 
                 if dir_path.is_dir() {
-                    file_info_vec.extend(list_directories(vec![dir_path])?);
+                    file_info_vec.extend(list_recursively(vec![dir_path], similarity, on_file)?);
                 } else if dir_path.to_str().is_some() {
-                    if let Some(file_info) = create_file_info(&dir_path) {
+                    if let Some(file_info) = create_file_info(&dir_path, similarity) {
                         info!("Found file {}", file_info.path.display());
+                        on_file(&file_info);
                         file_info_vec.push(file_info);
                     }
                 }
@@ -37,8 +168,12 @@ mod tests {
     use super::*;
 
     #[test]
+    #[allow(clippy::cmp_owned)]
     fn valid() {
-        let res = list_directories(vec![PathBuf::from("tests/assets/test_folder")]);
+        let res = list_directories(
+            vec![PathBuf::from("tests/assets/test_folder")],
+            &SimilarityConfig::default(),
+        );
         if let Ok(file_infos) = res {
             assert!(file_infos.iter().any(|file_info| file_info.pretty_path
                 != PathBuf::from("tests/assets/test_folder/test-file-1")));
@@ -50,10 +185,27 @@ mod tests {
         }
     }
 
+    #[test]
+    fn scan_reports_progress() {
+        let progress = SharedScanProgress::default();
+        let files = scan_directories(
+            vec![PathBuf::from("tests/assets/test_folder")],
+            &SimilarityConfig::default(),
+            &progress,
+        )
+        .unwrap();
+
+        let progress = progress.lock().unwrap();
+        assert_eq!(progress.state, ScanState::Complete);
+        assert_eq!(progress.total_files, files.len() as u64);
+        assert_eq!(progress.scanned_files, progress.total_files);
+        assert_eq!(progress.scanned_bytes, progress.total_bytes);
+    }
+
     #[test]
     fn empty_path() {
         assert!(matches!(
-            list_directories(vec![PathBuf::from("")]),
+            list_directories(vec![PathBuf::from("")], &SimilarityConfig::default()),
             Err(AgentError::NotADirectory())
         ));
     }
@@ -61,7 +213,10 @@ mod tests {
     #[test]
     fn file_does_not_exist() {
         assert!(matches!(
-            list_directories(vec![PathBuf::from("file-does-not-exist")]),
+            list_directories(
+                vec![PathBuf::from("file-does-not-exist")],
+                &SimilarityConfig::default()
+            ),
             Err(AgentError::NotADirectory())
         ));
     }
@@ -69,7 +224,10 @@ mod tests {
     #[test]
     fn is_reg_file() {
         assert!(matches!(
-            list_directories(vec![PathBuf::from("tests/assets/test_folder/test-file-1")]),
+            list_directories(
+                vec![PathBuf::from("tests/assets/test_folder/test-file-1")],
+                &SimilarityConfig::default()
+            ),
             Err(AgentError::NotADirectory())
         ));
     }
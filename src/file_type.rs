@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Enough for the magic bytes of every format `infer` knows and to tell text from binary
+const SNIFF_LEN: u64 = 8192;
+
+const TEXT: &str = "text/plain";
+const BINARY: &str = "application/octet-stream";
+
+// Containers of other formats, their contents can't disagree with the extension
+const CONTAINERS: [&str; 3] = ["application/zip", "application/x-ole-storage", BINARY];
+
+// Source files, some of whose extensions are also taken for something else, such as `ts` for MPEG streams
+const CODE_EXTENSIONS: [&str; 40] = [
+    "c", "cc", "cpp", "cs", "css", "dart", "go", "h", "hpp", "html", "java", "js", "json", "jsx",
+    "kt", "lua", "m", "php", "pl", "proto", "ps1", "py", "r", "rb", "rs", "scala", "scss", "sh",
+    "sql", "svelte", "swift", "toml", "ts", "tsx", "vue", "xml", "yaml", "yml", "bat", "ini",
+];
+
+const ARCHIVES: [&str; 17] = [
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-tar",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/vnd.rar",
+    "application/x-rar-compressed",
+    "application/zstd",
+    "application/x-compress",
+    "application/x-lzip",
+    "application/x-lz4",
+    "application/java-archive",
+    "application/vnd.ms-cab-compressed",
+    "application/x-iso9660-image",
+    "application/x-apple-diskimage",
+];
+
+const EXECUTABLES: [&str; 10] = [
+    "application/x-executable",
+    "application/x-elf",
+    "application/x-sharedlib",
+    "application/x-mach-binary",
+    "application/x-msdownload",
+    "application/vnd.microsoft.portable-executable",
+    "application/x-dosexec",
+    "application/vnd.android.package-archive",
+    "application/x-msi",
+    "application/wasm",
+];
+
+const DOCUMENTS: [&str; 12] = [
+    "application/pdf",
+    "application/rtf",
+    "application/msword",
+    "application/vnd.ms-excel",
+    "application/vnd.ms-powerpoint",
+    "application/vnd.openxmlformats-officedocument.",
+    "application/vnd.oasis.opendocument.",
+    "application/epub+zip",
+    "application/postscript",
+    "text/plain",
+    "text/csv",
+    "text/markdown",
+];
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileCategory {
+    Document,
+    Image,
+    Video,
+    Audio,
+    Archive,
+    Code,
+    Executable,
+    #[default]
+    Other,
+}
+
+impl FileCategory {
+    fn of(mime_type: &str) -> Self {
+        let is = |types: &[&str]| types.iter().any(|known| mime_type.starts_with(known));
+        match mime_type.split('/').next() {
+            Some("image") => Self::Image,
+            Some("video") => Self::Video,
+            Some("audio") => Self::Audio,
+            _ if is(&ARCHIVES) => Self::Archive,
+            _ if is(&EXECUTABLES) => Self::Executable,
+            _ if is(&DOCUMENTS) => Self::Document,
+            Some("text") => Self::Code,
+            _ if is(&[
+                "application/javascript",
+                "application/json",
+                "application/xml",
+            ]) =>
+            {
+                Self::Code
+            }
+            _ => Self::Other,
+        }
+    }
+}
+
+/// What a file is, from its first bytes and its extension
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileType {
+    pub mime_type: String,
+    pub category: FileCategory,
+    /// The contents are of another kind than the extension says
+    pub mismatch: bool,
+}
+
+pub fn detect(path: &Path) -> io::Result<FileType> {
+    let mut header = Vec::new();
+    fs::File::open(path)?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut header)?;
+    Ok(classify(path, &header))
+}
+
+fn classify(path: &Path, header: &[u8]) -> FileType {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let is_code = extension
+        .as_deref()
+        .is_some_and(|extension| CODE_EXTENSIONS.contains(&extension));
+    let guessed_mime = mime_guess::from_path(path)
+        .first()
+        .map(|mime| mime.essence_str().to_owned());
+    let guessed = if is_code {
+        // Only the binary types the extension is also taken for are replaced
+        let mime_type = guessed_mime
+            .filter(|mime| {
+                mime != BINARY
+                    && matches!(
+                        FileCategory::of(mime),
+                        FileCategory::Code | FileCategory::Document | FileCategory::Other
+                    )
+            })
+            .unwrap_or_else(|| TEXT.to_owned());
+        Some((mime_type, FileCategory::Code))
+    } else {
+        guessed_mime.map(|mime| {
+            let category = FileCategory::of(&mime);
+            (mime, category)
+        })
+    };
+    let sniffed = infer::get(header).map(|kind| kind.mime_type());
+
+    let mismatch = match (sniffed, &guessed) {
+        (Some(sniffed), Some((_, guessed_category))) => {
+            !CONTAINERS.contains(&sniffed) && FileCategory::of(sniffed) != *guessed_category
+        }
+        // Text holds no magic bytes, only binary contents can give it away
+        (None, Some((guessed, category))) => {
+            (*category == FileCategory::Code || guessed.starts_with("text/")) && header.contains(&0)
+        }
+        _ => false,
+    };
+
+    let (mime_type, category) = match (sniffed, guessed) {
+        (Some(sniffed), Some(guessed)) if CONTAINERS.contains(&sniffed) => guessed,
+        (Some(sniffed), _) => (sniffed.to_owned(), FileCategory::of(sniffed)),
+        (None, Some(guessed)) if !mismatch => guessed,
+        (None, _) if header.is_empty() || header.contains(&0) => {
+            (BINARY.to_owned(), FileCategory::Other)
+        }
+        (None, _) => (TEXT.to_owned(), FileCategory::Document),
+    };
+    FileType {
+        mime_type,
+        category,
+        mismatch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn contents_and_extension_are_checked_against_each_other() {
+        let png = classify(Path::new("photo.png"), PNG);
+        assert_eq!(png.mime_type, "image/png");
+        assert_eq!(png.category, FileCategory::Image);
+        assert!(!png.mismatch);
+
+        let source = classify(Path::new("main.ts"), b"export const answer = 42;\n");
+        assert_eq!(source.mime_type, TEXT);
+        assert_eq!(source.category, FileCategory::Code);
+        assert!(!source.mismatch);
+
+        let data = classify(Path::new("package.json"), b"{\"name\": \"agent\"}\n");
+        assert_eq!(data.mime_type, "application/json");
+        assert_eq!(data.category, FileCategory::Code);
+
+        let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+        elf.resize(64, 0);
+        let disguised = classify(Path::new("invoice.pdf"), &elf);
+        assert_eq!(disguised.category, FileCategory::Executable);
+        assert!(disguised.mismatch);
+
+        let renamed = classify(Path::new("notes.txt"), PNG);
+        assert_eq!(renamed.category, FileCategory::Image);
+        assert!(renamed.mismatch);
+
+        let unknown = classify(Path::new("README"), b"Plain words\n");
+        assert_eq!(unknown.mime_type, TEXT);
+        assert!(!unknown.mismatch);
+    }
+}
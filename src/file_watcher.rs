@@ -1,54 +1,159 @@
 // use notify::Watcher;
-use notify_debouncer_full::new_debouncer;
+use notify_debouncer_full::{
+    new_debouncer, notify::RecommendedWatcher, DebounceEventResult, Debouncer, RecommendedCache,
+};
 use std::path::PathBuf;
 use std::sync::mpsc;
-use std::time;
+use std::{thread, time};
 use tokio::sync::mpsc::UnboundedSender;
-use tracing::error;
+use tracing::{error, info};
 
-pub fn watch_directories(
+use crate::health::SharedAgentHealth;
+use crate::telemetry;
+
+enum Message {
+    Events(DebounceEventResult),
+    Watch(Vec<PathBuf>),
+}
+
+/// Changes the directories of a running watcher
+#[derive(Clone)]
+pub struct WatcherHandle {
+    messages: mpsc::Sender<Message>,
+}
+
+impl WatcherHandle {
+    pub fn watch(&self, directories: Vec<PathBuf>) {
+        if self.messages.send(Message::Watch(directories)).is_err() {
+            error!("The file watcher is not running anymore");
+        }
+    }
+}
+
+// Marks the watcher as stopped when its thread ends, even by panicking
+struct Running(SharedAgentHealth);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut health = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // A watcher that never started already recorded why
+        if health.watcher.ready {
+            error!("The file watcher stopped");
+            health.watcher.failure(&"The file watcher stopped");
+        }
+    }
+}
+
+pub fn spawn(
     directories: Vec<PathBuf>,
     sender: UnboundedSender<notify_debouncer_full::DebouncedEvent>,
-) {
-    let (tx, rx) = mpsc::channel();
-
-    let mut debouncer: notify_debouncer_full::Debouncer<
-        notify_debouncer_full::notify::INotifyWatcher,
-        notify_debouncer_full::NoCache,
-    > = match new_debouncer(time::Duration::from_secs(2), None, tx) {
-        Ok(debouncer) => debouncer,
-        Err(err) => {
-            error!("{:?}", err);
-            return;
-        }
+    health: SharedAgentHealth,
+) -> WatcherHandle {
+    let (messages_sender, messages) = mpsc::channel();
+    let handle = WatcherHandle {
+        messages: messages_sender.clone(),
     };
+    thread::spawn(move || {
+        watch_directories(directories, sender, messages_sender, messages, health)
+    });
+    handle
+}
 
-    for directory in directories {
-        let clean_directory = match directory.canonicalize() {
-            Ok(clean_directory) => clean_directory,
+fn watch_directories(
+    directories: Vec<PathBuf>,
+    sender: UnboundedSender<notify_debouncer_full::DebouncedEvent>,
+    messages_sender: mpsc::Sender<Message>,
+    messages: mpsc::Receiver<Message>,
+    health: SharedAgentHealth,
+) {
+    let running = Running(health);
+    let mut debouncer: Debouncer<RecommendedWatcher, RecommendedCache> =
+        match new_debouncer(time::Duration::from_secs(2), None, move |result| {
+            let _ = messages_sender.send(Message::Events(result));
+        }) {
+            Ok(debouncer) => debouncer,
             Err(err) => {
-                error!("error with {:?}: {:?}", directory, err);
-                continue;
+                error!("{:?}", err);
+                running.0.lock().unwrap().watcher.error(&err);
+                return;
             }
         };
 
-        if let Err(err) = debouncer.watch(&clean_directory, notify::RecursiveMode::Recursive) {
-            error!("{:?}: {:?}", clean_directory, err);
-        }
-    }
-
-    for result in rx {
-        match result {
-            Ok(events) => {
+    let mut watched = retarget(&mut debouncer, Vec::new(), directories, &running.0);
+    running.0.lock().unwrap().watcher.ready();
+    for message in messages {
+        match message {
+            Message::Events(Ok(events)) => {
                 for event in &events {
+                    telemetry::watcher_event(&event.kind);
+                    telemetry::event_queued();
                     sender.send(event.clone()).unwrap();
                 }
             }
-            Err(errors) => {
+            Message::Events(Err(errors)) => {
                 for error in &errors {
                     error!("{error:?}");
+                    running.0.lock().unwrap().watcher.error(error);
                 }
             }
+            Message::Watch(directories) => {
+                watched = retarget(&mut debouncer, watched, directories, &running.0);
+            }
         }
     }
 }
+
+// Unwatches the directories that are gone, watches the new ones and returns the watched set
+fn retarget(
+    debouncer: &mut Debouncer<RecommendedWatcher, RecommendedCache>,
+    watched: Vec<PathBuf>,
+    directories: Vec<PathBuf>,
+    health: &SharedAgentHealth,
+) -> Vec<PathBuf> {
+    let mut clean_directories = Vec::new();
+    for directory in directories {
+        match directory.canonicalize() {
+            Ok(clean_directory) => clean_directories.push(clean_directory),
+            Err(err) => {
+                error!("error with {:?}: {:?}", directory, err);
+                health
+                    .lock()
+                    .unwrap()
+                    .watcher
+                    .error(&format!("{}: {}", directory.display(), err));
+            }
+        }
+    }
+
+    for directory in watched.iter() {
+        if !clean_directories.contains(directory) {
+            info!("No longer watching {}", directory.display());
+            if let Err(err) = debouncer.unwatch(directory) {
+                error!("{:?}: {:?}", directory, err);
+            }
+        }
+    }
+    clean_directories
+        .into_iter()
+        .filter(|clean_directory| {
+            if watched.contains(clean_directory) {
+                return true;
+            }
+            match debouncer.watch(clean_directory, notify::RecursiveMode::Recursive) {
+                Ok(()) => true,
+                Err(err) => {
+                    error!("{:?}: {:?}", clean_directory, err);
+                    health.lock().unwrap().watcher.error(&format!(
+                        "{}: {}",
+                        clean_directory.display(),
+                        err
+                    ));
+                    false
+                }
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,127 @@
+use crate::http::connection::{ConnectionState, HubConnectionStatus, SharedHubConnectionStatus};
+use serde_derive::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// State of a part of the agent it needs to be ready, shared with the local API
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct SubsystemStatus {
+    pub ready: bool,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<SystemTime>,
+}
+
+impl SubsystemStatus {
+    pub fn ready(&mut self) {
+        self.ready = true;
+    }
+
+    /// Records an error the subsystem recovers from
+    pub fn error(&mut self, error: &dyn std::fmt::Display) {
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(SystemTime::now());
+    }
+
+    pub fn failure(&mut self, error: &dyn std::fmt::Display) {
+        self.ready = false;
+        self.error(error);
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct AgentHealth {
+    pub watcher: SubsystemStatus,
+    pub initial_scan: SubsystemStatus,
+}
+
+pub type SharedAgentHealth = Arc<Mutex<AgentHealth>>;
+
+/// The agent is ready once it watches the directories, has scanned them and holds a Hub session
+pub fn is_ready(health: &AgentHealth, hub: &HubConnectionStatus) -> bool {
+    health.watcher.ready && health.initial_scan.ready && hub.state == ConnectionState::Connected
+}
+
+/// Tells systemd when the agent is ready, then keeps its watchdog fed while it stays ready
+#[cfg(unix)]
+pub fn notify_systemd(health: SharedAgentHealth, hub: SharedHubConnectionStatus) {
+    use std::env;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::Duration;
+    use tracing::{error, info};
+
+    let Ok(socket_path) = env::var("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(err) => {
+            error!("Could not notify systemd: {}", err);
+            return;
+        }
+    };
+    let notify = move |state: &str| {
+        let sent = match socket_path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                std::os::unix::net::SocketAddr::from_abstract_name(name)
+                    .and_then(|address| socket.send_to_addr(state.as_bytes(), &address))
+            }
+            _ => socket.send_to(state.as_bytes(), &socket_path),
+        };
+        if let Err(err) = sent {
+            error!("Could not notify systemd: {}", err);
+        }
+    };
+    // The watchdog must be fed at least every WATCHDOG_USEC, half of it leaves some slack
+    let interval = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .map(|usec| Duration::from_micros(usec / 2));
+
+    tokio::spawn(async move {
+        let mut notified_ready = false;
+        loop {
+            let ready = is_ready(&health.lock().unwrap(), &hub.lock().unwrap());
+            if ready && !notified_ready {
+                info!("Notifying systemd that the agent is ready");
+                notify("READY=1");
+                notified_ready = true;
+            }
+            match interval {
+                Some(interval) => {
+                    if ready {
+                        notify("WATCHDOG=1");
+                    }
+                    tokio::time::sleep(interval).await;
+                }
+                None if notified_ready => break,
+                None => tokio::time::sleep(Duration::from_secs(1)).await,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_needs_every_subsystem() {
+        let mut health = AgentHealth::default();
+        let mut hub = HubConnectionStatus::default();
+        hub.connected();
+        health.initial_scan.ready();
+        assert!(!is_ready(&health, &hub));
+
+        health.watcher.ready();
+        assert!(is_ready(&health, &hub));
+
+        health.watcher.failure(&"The file watcher stopped");
+        assert!(!is_ready(&health, &hub));
+        assert_eq!(
+            health.watcher.last_error.as_deref(),
+            Some("The file watcher stopped")
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use crate::agent_data::AgentData;
+use crate::configuration::Configuration;
+use crate::http::grpc::tidybee_events::HubInstruction;
+use crate::http::hub::Hub;
+use crate::telemetry;
+use std::future;
+use std::time::Duration;
+use tokio::time::{self, Instant, Interval, MissedTickBehavior};
+use tracing::{debug, warn};
+
+/// Paces the heartbeats at `heartbeat_interval_secs`, never ticking when they are disabled
+pub struct Heartbeat {
+    interval_secs: u64,
+    interval: Option<Interval>,
+}
+
+impl Heartbeat {
+    pub fn new(config: &Configuration) -> Self {
+        let interval_secs = config.hub_config.grpc_server.heartbeat_interval_secs;
+        let interval = (interval_secs > 0).then(|| {
+            let period = Duration::from_secs(interval_secs);
+            let mut interval = time::interval_at(Instant::now() + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Self {
+            interval_secs,
+            interval,
+        }
+    }
+
+    pub fn reconfigure(&mut self, config: &Configuration) {
+        if config.hub_config.grpc_server.heartbeat_interval_secs != self.interval_secs {
+            *self = Self::new(config);
+        }
+    }
+
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+/// Sends a heartbeat, returning the instructions of the Hub
+pub async fn beat(
+    hub: &mut Hub,
+    agent_data: &mut AgentData,
+    config: &Configuration,
+) -> Vec<HubInstruction> {
+    agent_data.update();
+    agent_data.set_watched_directories(config.filesystem_interface_config.dir.clone());
+    let message = agent_data.to_message(telemetry::pending_events());
+    match hub.grpc_client.heartbeat(message).await {
+        Ok(instructions) => {
+            debug!("Heartbeat sent, the Hub asked for {:?}", instructions);
+            instructions
+        }
+        Err(err) => {
+            warn!("{}", err);
+            Vec::new()
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use crate::configuration::{Configuration, LoggerConfig, ServerConfig};
+use crate::error::AgentError;
+use crate::file_index::FileIndex;
+use crate::file_lister;
+use crate::file_watcher::WatcherHandle;
+use crate::http::compatibility::FILE_EVENTS;
+use crate::http::hub::Hub;
+use crate::logging::Logging;
+use crate::validation::{error_count, log_diagnostics, validate};
+use notify_debouncer_full::{
+    new_debouncer, notify::RecommendedWatcher, DebounceEventResult, Debouncer, RecommendedCache,
+};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tracing::{error, info, warn};
+
+/// Watched directories the Hub added or removed, applied over the configuration files until a restart
+#[derive(Debug, Default, Clone)]
+struct RootOverrides {
+    added: Vec<PathBuf>,
+    removed: Vec<PathBuf>,
+}
+
+impl RootOverrides {
+    fn apply(&self, config: &mut Configuration) {
+        let dirs = &mut config.filesystem_interface_config.dir;
+        dirs.retain(|dir| !self.removed.iter().any(|removed| same_dir(dir, removed)));
+        for added in &self.added {
+            if !dirs.iter().any(|dir| same_dir(dir, added)) {
+                dirs.push(added.clone());
+            }
+        }
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> bool {
+    a == b
+        || a.canonicalize()
+            .is_ok_and(|a| b.canonicalize().is_ok_and(|b| a == b))
+}
+
+/// Reloads or changes the published configuration from within the agent
+#[derive(Clone)]
+pub struct ConfigHandle {
+    trigger: mpsc::UnboundedSender<&'static str>,
+    config: Arc<watch::Sender<Configuration>>,
+    overrides: Arc<Mutex<RootOverrides>>,
+}
+
+impl ConfigHandle {
+    /// Not tied to any reload task
+    #[cfg(test)]
+    pub fn detached(config: Configuration) -> Self {
+        let (trigger, _) = mpsc::unbounded_channel();
+        Self {
+            trigger,
+            config: Arc::new(watch::channel(config).0),
+            overrides: Arc::default(),
+        }
+    }
+
+    pub fn reload(&self, reason: &'static str) {
+        if self.trigger.send(reason).is_err() {
+            error!("The configuration reload task is not running anymore");
+        }
+    }
+
+    pub fn add_watch_root(&self, root: PathBuf) -> Result<(), String> {
+        self.change_roots(|overrides| {
+            overrides
+                .removed
+                .retain(|removed| !same_dir(removed, &root));
+            overrides.added.push(root);
+        })
+    }
+
+    pub fn remove_watch_root(&self, root: &Path) -> Result<(), String> {
+        self.change_roots(|overrides| {
+            overrides.added.retain(|added| !same_dir(added, root));
+            overrides.removed.push(root.to_owned());
+        })
+    }
+
+    /// Whether `root` is watched because the Hub added it
+    pub fn added_by_hub(&self, root: &Path) -> bool {
+        let overrides = self.overrides.lock().unwrap();
+        overrides.added.iter().any(|added| same_dir(added, root))
+    }
+
+    /// Publishes the configuration in effect in place of `requested`, unless another one came in since
+    pub fn settle(&self, requested: &Configuration, applied: &Configuration) {
+        self.config.send_if_modified(|current| {
+            if current != requested || current == applied {
+                return false;
+            }
+            *current = applied.clone();
+            true
+        });
+    }
+
+    // Publishes the change only when the resulting configuration is valid
+    fn change_roots(&self, change: impl FnOnce(&mut RootOverrides)) -> Result<(), String> {
+        let mut overrides = self.overrides.lock().unwrap();
+        let mut changed = overrides.clone();
+        change(&mut changed);
+
+        let mut new_config = self.config.borrow().clone();
+        changed.apply(&mut new_config);
+        let diagnostics = validate(&new_config);
+        if error_count(&diagnostics) > 0 {
+            log_diagnostics(&diagnostics);
+            return Err("The configuration would have errors".to_owned());
+        }
+        *overrides = changed;
+        self.config.send_replace(new_config);
+        Ok(())
+    }
+}
+
+/// Reloads the configuration on SIGHUP, when one of `files` changes or when triggered, publishing it when it is valid
+pub fn spawn(
+    files: Vec<PathBuf>,
+    load: impl Fn() -> Result<Configuration, AgentError> + Send + 'static,
+    config: watch::Sender<Configuration>,
+) -> ConfigHandle {
+    let (trigger, mut triggers) = mpsc::unbounded_channel();
+    let config = Arc::new(config);
+    let overrides = Arc::new(Mutex::new(RootOverrides::default()));
+    let handle = ConfigHandle {
+        trigger: trigger.clone(),
+        config: config.clone(),
+        overrides: overrides.clone(),
+    };
+    let debouncer = watch_files(files, trigger.clone());
+    #[cfg(unix)]
+    forward_sighup(trigger);
+
+    tokio::spawn(async move {
+        // Dropping the debouncer would stop watching the files
+        let _debouncer = debouncer;
+        while let Some(reason) = triggers.recv().await {
+            info!("Reloading the configuration: {}", reason);
+            reload(&load, &config, &overrides);
+        }
+    });
+    handle
+}
+
+fn reload(
+    load: &impl Fn() -> Result<Configuration, AgentError>,
+    config: &watch::Sender<Configuration>,
+    overrides: &Mutex<RootOverrides>,
+) {
+    let mut new_config = match load() {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            error!("Keeping the current configuration: {}", err);
+            return;
+        }
+    };
+    // Held until published so that the Hub does not change the roots in between
+    let overrides = overrides.lock().unwrap();
+    overrides.apply(&mut new_config);
+    let diagnostics = validate(&new_config);
+    log_diagnostics(&diagnostics);
+    if error_count(&diagnostics) > 0 {
+        error!("Keeping the current configuration, the new one has errors");
+        return;
+    }
+
+    let modified = config.send_if_modified(|current| {
+        if *current == new_config {
+            return false;
+        }
+        *current = new_config;
+        true
+    });
+    if !modified {
+        info!("The configuration did not change");
+    }
+}
+
+fn watch_files(
+    files: Vec<PathBuf>,
+    trigger: mpsc::UnboundedSender<&'static str>,
+) -> Option<Debouncer<RecommendedWatcher, RecommendedCache>> {
+    // Editors replace files rather than writing them, so their directories are watched
+    let mut dirs: Vec<PathBuf> = Vec::new();
+    let mut watched_files: Vec<PathBuf> = Vec::new();
+    for file in &files {
+        let dir = match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let (Ok(dir), Some(name)) = (dir.canonicalize(), file.file_name()) else {
+            warn!("Not watching {} for changes", file.display());
+            continue;
+        };
+        watched_files.push(dir.join(name));
+        if !dirs.contains(&dir) {
+            dirs.push(dir);
+        }
+    }
+    if dirs.is_empty() {
+        return None;
+    }
+
+    let debouncer = new_debouncer(
+        Duration::from_millis(500),
+        None,
+        move |result: DebounceEventResult| {
+            let Ok(events) = result else { return };
+            if events.iter().any(|event| {
+                !event.kind.is_access()
+                    && event.paths.iter().any(|path| watched_files.contains(path))
+            }) {
+                let _ = trigger.send("a configuration file changed");
+            }
+        },
+    );
+    let mut debouncer = match debouncer {
+        Ok(debouncer) => debouncer,
+        Err(err) => {
+            error!("Could not watch the configuration files: {}", err);
+            return None;
+        }
+    };
+    for dir in &dirs {
+        if let Err(err) = debouncer.watch(dir, notify::RecursiveMode::NonRecursive) {
+            error!("Could not watch {} for changes: {}", dir.display(), err);
+        }
+    }
+    Some(debouncer)
+}
+
+#[cfg(unix)]
+fn forward_sighup(trigger: mpsc::UnboundedSender<&'static str>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            error!("Could not listen for SIGHUP: {}", err);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            if trigger.send("received SIGHUP").is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Whether going from `previous` to `config` takes connecting to the Hub again
+pub fn hub_changed(previous: &Configuration, config: &Configuration) -> bool {
+    // The heartbeat interval applies without reconnecting
+    let mut hub_config = config.hub_config.clone();
+    hub_config.grpc_server.heartbeat_interval_secs =
+        previous.hub_config.grpc_server.heartbeat_interval_secs;
+    hub_config != previous.hub_config || config.agent_data != previous.agent_data
+}
+
+/// Applies what changed between two configurations but the Hub, returning the one actually in effect
+pub async fn apply(
+    previous: &Configuration,
+    config: &Configuration,
+    hub: &mut Hub,
+    watcher: &WatcherHandle,
+    index: &FileIndex,
+    logging: &Logging,
+) -> Configuration {
+    let mut applied = config.clone();
+
+    let logger_config = &config.logger_config;
+    if logger_config.term_level != previous.logger_config.term_level {
+        logging.set_term_level(&logger_config.term_level);
+    }
+    if logger_config.file_level != previous.logger_config.file_level {
+        logging.set_file_level(&logger_config.file_level);
+    }
+
+    let dirs = &config.filesystem_interface_config.dir;
+    if *dirs != previous.filesystem_interface_config.dir {
+        info!("Watching {:?}", dirs);
+        watcher.watch(dirs.clone());
+        let roots: Vec<PathBuf> = dirs
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .collect();
+        index.retain_roots(&roots);
+        let added: Vec<PathBuf> = dirs
+            .iter()
+            .filter(|dir| !previous.filesystem_interface_config.dir.contains(dir))
+            .cloned()
+            .collect();
+        if !added.is_empty() {
+            // Hashing can take a while, it is kept off the async workers, root by root so that an unreadable one spares the others
+            let similarity = config.similarity_config.clone();
+            let listing = tokio::task::spawn_blocking(move || {
+                let mut files = Vec::new();
+                for dir in added {
+                    match file_lister::list_directories(vec![dir], &similarity) {
+                        Ok(listed) => files.extend(listed),
+                        Err(err) => error!("{}", err),
+                    }
+                }
+                files
+            });
+            match listing.await {
+                Ok(files) => {
+                    index.insert(files.clone());
+                    if hub.allows(FILE_EVENTS) {
+                        if let Err(err) = hub.grpc_client.send_create_events_once(files).await {
+                            error!("{err}");
+                        }
+                    }
+                }
+                Err(err) => error!("Listing the new directories failed: {}", err),
+            }
+        }
+    }
+
+    let log_files_changed = logger_config.dir != previous.logger_config.dir
+        || logger_config.rotation != previous.logger_config.rotation
+        || logger_config.max_files != previous.logger_config.max_files
+        || logger_config.json != previous.logger_config.json
+        || (logger_config.file_level == "off") != (previous.logger_config.file_level == "off");
+    // The API keys are read on every request, the rest of server_config is bound at startup
+    if config.server_config.address != previous.server_config.address
+        || config.server_config.log_level != previous.server_config.log_level
+        || config.state_config != previous.state_config
+        || log_files_changed
+    {
+        warn!(
+            "Changes to server_config, state_config and the log files only apply after a restart"
+        );
+        applied.server_config = ServerConfig {
+            auth: config.server_config.auth.clone(),
+            ..previous.server_config.clone()
+        };
+        applied.state_config = previous.state_config.clone();
+        applied.logger_config = LoggerConfig {
+            term_level: logger_config.term_level.clone(),
+            file_level: logger_config.file_level.clone(),
+            ..previous.logger_config.clone()
+        };
+    }
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hub_roots_survive_reloads() {
+        let handle = ConfigHandle::detached(Configuration::default());
+        let receiver = handle.config.subscribe();
+        let added = PathBuf::from("tests/assets");
+
+        handle.add_watch_root(added.clone()).unwrap();
+        assert!(handle.add_watch_root("does/not/exist".into()).is_err());
+        reload(
+            &|| Ok(Configuration::default()),
+            &handle.config,
+            &handle.overrides,
+        );
+        assert!(receiver
+            .borrow()
+            .filesystem_interface_config
+            .dir
+            .contains(&added));
+
+        handle.remove_watch_root(&added).unwrap();
+        reload(
+            &|| Ok(Configuration::default()),
+            &handle.config,
+            &handle.overrides,
+        );
+        assert!(!receiver
+            .borrow()
+            .filesystem_interface_config
+            .dir
+            .contains(&added));
+    }
+
+    #[test]
+    fn only_the_requested_configuration_is_settled() {
+        let requested = Configuration::default();
+        let handle = ConfigHandle::detached(requested.clone());
+        let receiver = handle.config.subscribe();
+        let mut applied = requested.clone();
+        applied.hub_config.port = "9999".to_owned();
+
+        handle.settle(&requested, &applied);
+        assert_eq!(*receiver.borrow(), applied);
+
+        // A newer configuration came in meanwhile
+        handle.settle(&requested, &Configuration::default());
+        assert_eq!(*receiver.borrow(), applied);
+    }
+}
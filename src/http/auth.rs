@@ -0,0 +1,116 @@
+use crate::configuration::{ApiAuthConfig, Configuration};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::net::SocketAddr;
+use tokio::sync::watch;
+use tracing::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Admin,
+}
+
+/// Requires `required` access to reach the routes it is layered on
+#[derive(Clone)]
+pub struct AuthState {
+    pub config: watch::Receiver<Configuration>,
+    pub required: Access,
+}
+
+pub async fn authorize(
+    State(auth): State<AuthState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Keys are read on every request so that reloading the configuration rotates them
+    let granted = granted_access(
+        &auth.config.borrow().server_config.auth,
+        &peer,
+        request.headers(),
+    );
+    match granted {
+        Some(access) if access >= auth.required => next.run(request).await,
+        Some(_) => StatusCode::FORBIDDEN.into_response(),
+        None => {
+            warn!("Rejected unauthenticated request from {}", peer);
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+            )
+                .into_response()
+        }
+    }
+}
+
+// Without keys the API is left to local clients
+fn granted_access(auth: &ApiAuthConfig, peer: &SocketAddr, headers: &HeaderMap) -> Option<Access> {
+    if auth.read_keys.is_empty() && auth.admin_keys.is_empty() {
+        return peer.ip().is_loopback().then_some(Access::Admin);
+    }
+
+    let key = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?;
+    if auth
+        .admin_keys
+        .iter()
+        .any(|admin_key| keys_match(admin_key, key))
+    {
+        Some(Access::Admin)
+    } else if auth
+        .read_keys
+        .iter()
+        .any(|read_key| keys_match(read_key, key))
+    {
+        Some(Access::Read)
+    } else {
+        None
+    }
+}
+
+// Compares every byte so that the time taken doesn't tell how much of the key is right
+fn keys_match(expected: &str, presented: &str) -> bool {
+    expected.len() == presented.len()
+        && expected
+            .bytes()
+            .zip(presented.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_grant_their_access_level() {
+        let remote: SocketAddr = "192.168.1.2:4000".parse().unwrap();
+        let local: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+
+        let open = ApiAuthConfig::default();
+        assert_eq!(granted_access(&open, &local, &headers), Some(Access::Admin));
+        assert_eq!(granted_access(&open, &remote, &headers), None);
+
+        let auth = ApiAuthConfig {
+            read_keys: vec!["reader".to_owned()],
+            admin_keys: vec!["admin".to_owned()],
+        };
+        assert_eq!(granted_access(&auth, &local, &headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer reader".parse().unwrap());
+        assert_eq!(granted_access(&auth, &remote, &headers), Some(Access::Read));
+        headers.insert(header::AUTHORIZATION, "Bearer admin".parse().unwrap());
+        assert_eq!(
+            granted_access(&auth, &remote, &headers),
+            Some(Access::Admin)
+        );
+        headers.insert(header::AUTHORIZATION, "Bearer admix".parse().unwrap());
+        assert_eq!(granted_access(&auth, &remote, &headers), None);
+    }
+}
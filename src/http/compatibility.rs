@@ -0,0 +1,147 @@
+use semver::Version;
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Version of the messages exchanged with the Hub, raised when they change in a breaking way
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const AGENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const FILE_EVENTS: &str = "file_events";
+pub const HEARTBEAT: &str = "heartbeat";
+pub const COMMAND_CHANNEL: &str = "command_channel";
+
+// The agent works without them when the Hub does not support them
+const OPTIONAL_FEATURES: [&str; 2] = [HEARTBEAT, COMMAND_CHANNEL];
+
+/// What the Hub tells about itself when the agent registers, older Hubs tell nothing
+#[derive(Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct HubRequirements {
+    #[serde(default, alias = "hubVersion", alias = "HubVersion")]
+    pub hub_version: Option<String>,
+    #[serde(default, alias = "minimalAgentVersion", alias = "MinimalAgentVersion")]
+    pub minimal_agent_version: Option<String>,
+    #[serde(
+        default,
+        alias = "minimalProtocolVersion",
+        alias = "MinimalProtocolVersion"
+    )]
+    pub minimal_protocol_version: Option<u32>,
+    #[serde(default, alias = "Features")]
+    pub features: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum Compatibility {
+    #[default]
+    Compatible,
+    /// Features of the agent the Hub does not support, left off
+    Degraded { disabled_features: Vec<String> },
+    /// Nothing is sent to the Hub
+    Incompatible { reason: String },
+}
+
+impl Compatibility {
+    pub fn allows(&self, feature: &str) -> bool {
+        match self {
+            Self::Compatible => true,
+            Self::Degraded { disabled_features } => {
+                !disabled_features.iter().any(|disabled| disabled == feature)
+            }
+            Self::Incompatible { .. } => false,
+        }
+    }
+}
+
+/// Checks the versions of both sides, `minimal_hub_version` being the oldest Hub the agent works with
+pub fn negotiate(hub: &HubRequirements, minimal_hub_version: &str) -> Compatibility {
+    if let Some(minimal_agent_version) = &hub.minimal_agent_version {
+        if older(AGENT_VERSION, minimal_agent_version) {
+            return Compatibility::Incompatible {
+                reason: format!(
+                    "The Hub requires agent version {} or later, this agent is {}",
+                    minimal_agent_version, AGENT_VERSION
+                ),
+            };
+        }
+    }
+    if let Some(minimal_protocol_version) = hub.minimal_protocol_version {
+        if PROTOCOL_VERSION < minimal_protocol_version {
+            return Compatibility::Incompatible {
+                reason: format!(
+                    "The Hub requires protocol version {} or later, this agent speaks {}",
+                    minimal_protocol_version, PROTOCOL_VERSION
+                ),
+            };
+        }
+    }
+    if let Some(hub_version) = &hub.hub_version {
+        if !minimal_hub_version.is_empty() && older(hub_version, minimal_hub_version) {
+            return Compatibility::Incompatible {
+                reason: format!(
+                    "This agent requires Hub version {} or later, the Hub is {}",
+                    minimal_hub_version, hub_version
+                ),
+            };
+        }
+    }
+
+    let disabled_features: Vec<String> = match &hub.features {
+        Some(features) => OPTIONAL_FEATURES
+            .iter()
+            .filter(|feature| !features.iter().any(|supported| supported == *feature))
+            .map(|feature| (*feature).to_owned())
+            .collect(),
+        None => Vec::new(),
+    };
+    if disabled_features.is_empty() {
+        Compatibility::Compatible
+    } else {
+        Compatibility::Degraded { disabled_features }
+    }
+}
+
+// Versions that can't be parsed are not held against the other side
+fn older(version: &str, minimal: &str) -> bool {
+    match (Version::parse(version), Version::parse(minimal)) {
+        (Ok(version), Ok(minimal)) => version < minimal,
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(
+                "Could not compare versions {} and {}: {}",
+                version, minimal, err
+            );
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiation_checks_both_sides() {
+        let mut hub = HubRequirements::default();
+        assert_eq!(negotiate(&hub, "0.1.0"), Compatibility::Compatible);
+
+        hub.minimal_agent_version = Some("999.0.0".to_owned());
+        assert!(matches!(
+            negotiate(&hub, ""),
+            Compatibility::Incompatible { .. }
+        ));
+
+        hub.minimal_agent_version = Some(AGENT_VERSION.to_owned());
+        hub.hub_version = Some("0.0.1".to_owned());
+        assert!(matches!(
+            negotiate(&hub, "0.1.0"),
+            Compatibility::Incompatible { .. }
+        ));
+
+        hub.hub_version = Some("1.2.0".to_owned());
+        hub.features = Some(vec![FILE_EVENTS.to_owned(), HEARTBEAT.to_owned()]);
+        let compatibility = negotiate(&hub, "0.1.0");
+        assert!(compatibility.allows(HEARTBEAT));
+        assert!(!compatibility.allows(COMMAND_CHANNEL));
+    }
+}
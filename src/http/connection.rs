@@ -0,0 +1,190 @@
+use crate::configuration::HubConfig;
+use crate::error::HubError;
+use crate::http::compatibility::Compatibility;
+use rand::Rng;
+use reqwest::StatusCode;
+use serde_derive::Serialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+    Failed,
+    /// Connected, but the versions of the Hub and the agent don't match
+    Incompatible,
+}
+
+/// Connection state of the agent towards the Hub, shared with the local API
+#[derive(Debug, Serialize, Clone)]
+pub struct HubConnectionStatus {
+    pub state: ConnectionState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<SystemTime>,
+    pub next_retry_in_ms: Option<u64>,
+    pub connected_since: Option<SystemTime>,
+    pub compatibility: Option<Compatibility>,
+}
+
+impl Default for HubConnectionStatus {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Disconnected,
+            attempts: 0,
+            last_error: None,
+            last_error_at: None,
+            next_retry_in_ms: None,
+            connected_since: None,
+            compatibility: None,
+        }
+    }
+}
+
+pub type SharedHubConnectionStatus = Arc<Mutex<HubConnectionStatus>>;
+
+impl HubConnectionStatus {
+    pub fn attempt(&mut self) {
+        self.state = ConnectionState::Connecting;
+        self.attempts += 1;
+        self.next_retry_in_ms = None;
+    }
+
+    /// Gives the next connection phase its own attempts
+    pub fn reset_attempts(&mut self) {
+        self.attempts = 0;
+    }
+
+    pub fn failure(&mut self, error: &dyn std::fmt::Display, retry_in: Option<Duration>) {
+        self.state = if retry_in.is_some() {
+            ConnectionState::Connecting
+        } else {
+            ConnectionState::Failed
+        };
+        self.last_error = Some(error.to_string());
+        self.last_error_at = Some(SystemTime::now());
+        self.next_retry_in_ms = retry_in.map(|delay| delay.as_millis() as u64);
+        self.connected_since = None;
+    }
+
+    pub fn negotiated(&mut self, compatibility: Compatibility) {
+        if let Compatibility::Incompatible { reason } = &compatibility {
+            self.state = ConnectionState::Incompatible;
+            self.last_error = Some(reason.clone());
+            self.last_error_at = Some(SystemTime::now());
+            self.next_retry_in_ms = None;
+            self.connected_since = None;
+        }
+        self.compatibility = Some(compatibility);
+    }
+
+    pub fn connected(&mut self) {
+        self.state = ConnectionState::Connected;
+        self.attempts = 0;
+        self.next_retry_in_ms = None;
+        self.connected_since = Some(SystemTime::now());
+    }
+}
+
+/// Exponential backoff with proportional jitter between `initial` and `max`
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, jitter: f64) -> Self {
+        Self {
+            initial,
+            max: max.max(initial),
+            jitter: jitter.clamp(0.0, 1.0),
+            current: initial,
+        }
+    }
+
+    pub fn from_config(hub_config: &HubConfig) -> Self {
+        Self::new(
+            Duration::from_millis(hub_config.initial_backoff_ms),
+            Duration::from_millis(hub_config.max_backoff_ms),
+            hub_config.backoff_jitter,
+        )
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.current;
+        self.current = (self.current * 2).min(self.max);
+
+        if self.jitter == 0.0 {
+            return base;
+        }
+        let factor = 1.0 - self.jitter * rand::thread_rng().gen::<f64>();
+        base.mul_f64(factor)
+    }
+}
+
+// Client errors other than timeouts and throttling won't go away by asking again
+pub fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+impl HubError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            HubError::HttpError(err) => !err.is_decode() && !err.is_builder(),
+            HubError::UnexpectedStatus(status) => is_retryable_status(*status),
+            HubError::EventClientError(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5), 0.0);
+
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn backoff_jitter_stays_below_base() {
+        let mut backoff = Backoff::new(Duration::from_secs(8), Duration::from_secs(8), 0.5);
+
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay <= Duration::from_secs(8));
+            assert!(delay >= Duration::from_secs(4));
+        }
+    }
+
+    #[test]
+    fn status_classification() {
+        assert!(is_retryable_status(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(is_retryable_status(StatusCode::BAD_GATEWAY));
+        assert!(is_retryable_status(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable_status(StatusCode::UNAUTHORIZED));
+        assert!(!is_retryable_status(StatusCode::FORBIDDEN));
+        assert!(!is_retryable_status(StatusCode::NOT_FOUND));
+    }
+}
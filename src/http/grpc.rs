@@ -1,43 +1,120 @@
-use self::tidybee_events::{FileEventRequest, FileEventType};
+use self::tidybee_events::{
+    CommandResult, EventRejection, FileEventRequest, FileEventType, FileInfoEventResponse,
+    HeartbeatRequest, HubCommand, HubInstruction,
+};
 use crate::{
     configuration::GrpcServerConfig,
     error::GrpcClientError,
-    file_info::{self, FileInfo},
-    file_lister,
+    file_index::{OnDisk, ReadEvent},
+    file_info::FileInfo,
+    file_type::FileCategory,
+    http::{tls, token::TokenStore},
+    outbox::{Outbox, OutboxEvent},
+    telemetry,
 };
 
 use anyhow::{bail, ensure, Error, Result};
 use notify::event::ModifyKind;
 use notify_debouncer_full::DebouncedEvent;
-use std::{str::FromStr, vec};
+use std::{
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    vec,
+};
 use tidybee_events::{tidy_bee_events_client::TidyBeeEventsClient, FolderEventRequest};
-use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
     metadata::MetadataValue,
     service::Interceptor,
     transport::{Channel, Endpoint},
-    Request, Status,
+    Request, Response, Status, Streaming,
 };
 use tracing::{debug, info, warn};
+use xxhash_rust::xxh3::xxh3_128;
 
+// Generated code, some messages are not used by the agent yet
+#[allow(dead_code)]
 pub mod tidybee_events {
     tonic::include_proto!("tidybee_events");
 }
 
+// Results waiting to be sent on the command channel
+const COMMAND_RESULTS_BUFFER: usize = 64;
+
+// Times events the Hub rejected as retryable are sent again, waiting a bit longer each time
+const REJECTION_RETRIES: usize = 3;
+const REJECTION_RETRY_DELAY: Duration = Duration::from_millis(500);
+// Events numbered and sent in one stream, so that a large listing takes the outbox no further than it holds
+const EVENT_CHUNK: usize = 1000;
+
+pub fn created_event(file: FileInfo) -> FileEventRequest {
+    FileEventRequest {
+        event_type: FileEventType::Created as i32,
+        pretty_path: file.pretty_path.display().to_string(),
+        path: vec![file.path.display().to_string()],
+        size: Some(file.size),
+        hash: file.hash,
+        fingerprint: file.fingerprint,
+        image_hash: file.image_hash,
+        mime_type: file.mime_type,
+        category: tidybee_events::FileCategory::from(file.category) as i32,
+        type_mismatch: file.type_mismatch,
+        last_accessed: Some(file.last_accessed.into()),
+        last_modified: Some(file.last_modified.into()),
+        ..Default::default()
+    }
+}
+
+impl From<FileCategory> for tidybee_events::FileCategory {
+    fn from(category: FileCategory) -> Self {
+        match category {
+            FileCategory::Document => Self::Document,
+            FileCategory::Image => Self::Image,
+            FileCategory::Video => Self::Video,
+            FileCategory::Audio => Self::Audio,
+            FileCategory::Archive => Self::Archive,
+            FileCategory::Code => Self::Code,
+            FileCategory::Executable => Self::Executable,
+            FileCategory::Other => Self::Other,
+        }
+    }
+}
+
+pub fn deleted_event(path: &Path) -> FileEventRequest {
+    FileEventRequest {
+        event_type: FileEventType::Deleted as i32,
+        pretty_path: path.display().to_string(),
+        path: vec![path.display().to_string()],
+        ..Default::default()
+    }
+}
+
+// The debouncer only keeps the instant the watcher saw the event
+fn watcher_time(event: &DebouncedEvent) -> SystemTime {
+    SystemTime::now()
+        .checked_sub(event.time.elapsed())
+        .unwrap_or_else(SystemTime::now)
+}
+
 // region: --- Interceptors
 
 pub struct AuthInterceptor {
-    agent_uuid: String,
+    tokens: TokenStore,
 }
 
 impl Interceptor for AuthInterceptor {
     fn call(&mut self, mut request: tonic::Request<()>) -> Result<Request<()>, Status> {
-        debug!(
-            "Adding authorization header to gRPC request: {}",
-            self.agent_uuid
-        );
+        // The refresh task is woken up by the store when the token is about to expire
+        let token = match self.tokens.current() {
+            Some(token) => token,
+            None => return Err(Status::unauthenticated("No valid Hub access token")),
+        };
+        debug!("Adding authorization header to gRPC request");
 
-        match MetadataValue::from_str(format!("Bearer {}", self.agent_uuid).as_str()) {
+        match MetadataValue::from_str(format!("Bearer {}", token).as_str()) {
             Ok(auth_header) => {
                 request.metadata_mut().insert("authorization", auth_header);
                 Ok(request)
@@ -58,38 +135,122 @@ pub struct GrpcClient {
             tonic::service::interceptor::InterceptedService<Channel, AuthInterceptor>,
         >,
     >,
-    agent_uuid: Option<String>,
+    tokens: Option<TokenStore>,
+    outbox: Outbox,
+    // Some events failed to send, they go again once the Hub answers
+    resend: bool,
     endpoint: Endpoint,
+    tls: Option<(Arc<rustls::ClientConfig>, Option<String>)>,
 }
 
 impl GrpcClient {
     pub fn new(grpc_server_config: &GrpcServerConfig) -> Result<Self> {
+        let tls = match grpc_server_config.protocol.as_str() {
+            "https" => Some((
+                Arc::new(tls::client_config(&grpc_server_config.tls, &[b"h2"])?),
+                grpc_server_config.tls.server_name.clone(),
+            )),
+            _ => None,
+        };
         match Channel::from_shared(format!(
             "{}://{}:{}",
             grpc_server_config.protocol, grpc_server_config.host, grpc_server_config.port
         )) {
             Ok(endpoint) => Ok(Self {
                 client: None,
-                agent_uuid: None,
+                tokens: None,
+                outbox: Outbox::default(),
+                resend: false,
                 endpoint,
+                tls,
             }),
             Err(e) => bail!(e),
         }
     }
 
     #[inline]
-    pub fn set_agent_uuid(&mut self, agent_uuid: &String) {
-        info!("Setting agent uuid for gRPC client: {}", agent_uuid);
-        self.agent_uuid = Some(agent_uuid.clone());
+    pub fn set_token_store(&mut self, tokens: TokenStore) {
+        self.tokens = Some(tokens);
+    }
+
+    pub fn set_outbox(&mut self, outbox: Outbox) {
+        telemetry::event_sequence(outbox.last());
+        self.outbox = outbox;
+    }
+
+    pub fn take_outbox(&mut self, previous: &mut GrpcClient) {
+        self.set_outbox(std::mem::take(&mut previous.outbox));
+    }
+
+    /// Sends the events the Hub does not hold yet again, such as after reconnecting
+    pub async fn resend_pending(&mut self) -> Result<(), GrpcClientError> {
+        self.resend = false;
+        let pending = self.outbox.pending();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Sending {} event(s) the Hub may not have again",
+            pending.len()
+        );
+        let mut pending = pending.into_iter().peekable();
+        // Runs of one kind keep the order of the events
+        while let Some(event) = pending.next() {
+            match event {
+                OutboxEvent::File(event) => {
+                    let mut events = vec![event];
+                    while events.len() < EVENT_CHUNK {
+                        let Some(OutboxEvent::File(event)) =
+                            pending.next_if(|next| matches!(next, OutboxEvent::File(_)))
+                        else {
+                            break;
+                        };
+                        events.push(event);
+                    }
+                    self.deliver_file_events(events).await?;
+                }
+                OutboxEvent::Folder(event) => {
+                    let mut events = vec![event];
+                    while events.len() < EVENT_CHUNK {
+                        let Some(OutboxEvent::Folder(event)) =
+                            pending.next_if(|next| matches!(next, OutboxEvent::Folder(_)))
+                        else {
+                            break;
+                        };
+                        events.push(event);
+                    }
+                    self.deliver_folder_events(events).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // After a failure, sends the pending events again as soon as the Hub answers, so that its acknowledgement gets past the gap
+    async fn catch_up(&mut self) {
+        if !self.resend {
+            return;
+        }
+        if let Err(err) = self.resend_pending().await {
+            warn!("Could not send the pending events again: {}", err);
+        }
     }
 
     // Connect before setting interceptors !
     pub async fn connect(&mut self) -> Result<()> {
-        ensure!(
-            self.agent_uuid.is_some(),
-            GrpcClientError::AgentUuidNotSet()
-        );
-        let channel = match self.endpoint.connect().await {
+        ensure!(self.tokens.is_some(), GrpcClientError::TokenStoreNotSet());
+        let channel = match &self.tls {
+            Some((config, server_name)) => {
+                self.endpoint
+                    .connect_with_connector(tls::grpc_connector(
+                        config.clone(),
+                        server_name.clone(),
+                    ))
+                    .await
+            }
+            None => self.endpoint.connect().await,
+        };
+        let channel = match channel {
             Ok(channel) => channel,
             Err(e) => {
                 bail!(GrpcClientError::InvalidEndpoint(e));
@@ -97,7 +258,7 @@ impl GrpcClient {
         };
         info!("Connected to gRPC server");
         let interceptor = AuthInterceptor {
-            agent_uuid: self.agent_uuid.clone().unwrap(),
+            tokens: self.tokens.clone().unwrap(),
         };
         self.client = Some(TidyBeeEventsClient::with_interceptor(channel, interceptor));
         Ok(())
@@ -110,71 +271,96 @@ impl GrpcClient {
         if self.client.is_none() {
             return Err(GrpcClientError::ClientNotConnected());
         }
-        let stream = tokio_stream::iter(events.into_iter().map(|f| FileEventRequest {
-            event_type: FileEventType::Created as i32,
-            pretty_path: f.pretty_path.display().to_string(),
-            path: vec![f.path.display().to_string()],
-            size: Some(f.size),
-            hash: f.hash,
-            last_accessed: Some(f.last_accessed.into()),
-            last_modified: Some(f.last_modified.into()),
-        }));
-        let _ = self.client.as_mut().unwrap().file_event(stream).await;
-        Ok(())
+        self.send_file_events(events.into_iter().map(created_event), SystemTime::now())
+            .await
     }
 
-    pub async fn send_events(
+    /// Opens the stream on which the Hub sends commands, their results go to the returned sender
+    pub async fn open_command_channel(
         &mut self,
-        mut file_watcher_receiver: UnboundedReceiver<DebouncedEvent>,
-    ) -> Result<(), Error> {
-        if self.client.is_none() {
-            bail!(GrpcClientError::ClientNotConnected());
-        }
+    ) -> Result<(mpsc::Sender<CommandResult>, Streaming<HubCommand>), Status> {
+        let Some(client) = self.client.as_mut() else {
+            return Err(Status::unavailable(
+                GrpcClientError::ClientNotConnected().to_string(),
+            ));
+        };
+        let (results, outbound) = mpsc::channel(COMMAND_RESULTS_BUFFER);
+        let commands = client
+            .command_channel(ReceiverStream::new(outbound))
+            .await?
+            .into_inner();
+        Ok((results, commands))
+    }
 
-        while let Some(file_event) = file_watcher_receiver.recv().await {
-            if file_event.kind
-                == notify::event::EventKind::Access(notify::event::AccessKind::Open(
-                    notify::event::AccessMode::Any,
-                ))
-            {
-                continue;
+    /// Tells the Hub the agent is alive, returning what the Hub asks the agent to do
+    pub async fn heartbeat(
+        &mut self,
+        agent_data: tidybee_events::AgentData,
+    ) -> Result<Vec<HubInstruction>, GrpcClientError> {
+        let Some(client) = self.client.as_mut() else {
+            return Err(GrpcClientError::ClientNotConnected());
+        };
+        let request = HeartbeatRequest {
+            agent_data: Some(agent_data),
+            sent_at: Some(SystemTime::now().into()),
+        };
+        let start = Instant::now();
+        let result = client.heartbeat(request).await;
+        telemetry::grpc_request("heartbeat", start.elapsed());
+        let instructions = match result {
+            Ok(response) => response
+                .into_inner()
+                .instructions()
+                .filter(|instruction| *instruction != HubInstruction::NoInstruction)
+                .collect(),
+            Err(status) => return Err(GrpcClientError::HeartbeatError(status.to_string())),
+        };
+        self.catch_up().await;
+        Ok(instructions)
+    }
+
+    pub async fn send_event(&mut self, event: &ReadEvent) -> Result<(), Error> {
+        let result = self.forward_event(event).await;
+        // Events that failed to send or were rejected are already counted
+        if let Err(err) = &result {
+            if !matches!(
+                err.downcast_ref::<GrpcClientError>(),
+                Some(GrpcClientError::EventSendError() | GrpcClientError::EventsRejected(_))
+            ) {
+                telemetry::event_dropped();
             }
-            println!("{:?}", file_event);
-            match file_event.kind {
-                notify::EventKind::Create(notify::event::CreateKind::File) => {
-                    let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
-                        Some(info) => info,
-                        None => continue,
-                    };
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                    }
-                }
-                notify::EventKind::Modify(modify_kind) => {
-                    self.handle_modify_events(modify_kind, file_event).await?
-                }
-                notify::EventKind::Remove(remove_kind) => {
-                    self.handle_remove_events(remove_kind, file_event).await?
-                }
-                _ => (),
-            };
         }
+        result
+    }
+
+    // Without a client, the events are numbered and wait in the outbox for the connection
+    async fn forward_event(&mut self, event: &ReadEvent) -> Result<(), Error> {
+        let file_event = &event.event;
+        if file_event.kind
+            == notify::event::EventKind::Access(notify::event::AccessKind::Open(
+                notify::event::AccessMode::Any,
+            ))
+        {
+            return Ok(());
+        }
+        debug!("{:?}", file_event);
+        match file_event.kind {
+            notify::EventKind::Create(notify::event::CreateKind::File) => {
+                let OnDisk::File(info) = event.first() else {
+                    return Ok(());
+                };
+                let event = created_event(info.clone());
+                self.send_file_events(vec![event], watcher_time(file_event))
+                    .await?;
+            }
+            notify::EventKind::Modify(modify_kind) => {
+                self.handle_modify_events(modify_kind, event).await?
+            }
+            notify::EventKind::Remove(remove_kind) => {
+                self.handle_remove_events(remove_kind, file_event).await?
+            }
+            _ => (),
+        };
 
         Ok(())
     }
@@ -184,180 +370,69 @@ impl GrpcClient {
     async fn handle_modify_events(
         &mut self,
         modify_kind: notify::event::ModifyKind,
-        file_event: DebouncedEvent,
+        event: &ReadEvent,
     ) -> Result<(), Error> {
+        let file_event = &event.event;
+        let is_dir = matches!(event.first(), OnDisk::Directory | OnDisk::Tree(_));
         match modify_kind {
             ModifyKind::Data(_) => {
-                let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
-                    Some(info) => info,
-                    None => bail!(GrpcClientError::FileInfoError()),
+                let OnDisk::File(info) = event.first() else {
+                    bail!(GrpcClientError::FileInfoError());
                 };
-                let event = FileEventRequest {
-                    event_type: FileEventType::Created as i32,
-                    pretty_path: info.pretty_path.display().to_string(),
-                    path: vec![info.path.display().to_string()],
-                    size: Some(info.size),
-                    hash: info.hash,
-                    last_accessed: Some(info.last_accessed.into()),
-                    last_modified: Some(info.last_modified.into()),
-                };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .file_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
+                let event = created_event(info.clone());
+                self.send_file_events(vec![event], watcher_time(file_event))
+                    .await?;
             }
             // The ModifyKind::Name documentation is a bit unprecise, notify::event::RenameMode::To represent a new file or folder that was moved in the scope of the watcher
-            ModifyKind::Name(notify::event::RenameMode::To) => {
-                if file_event.paths[0].is_dir() {
-                    match file_lister::list_directories(vec![file_event.paths[0].clone()]) {
-                        Ok(file_info_vec) => {
-                            let events = file_info_vec.into_iter().map(|f| FileEventRequest {
-                                event_type: FileEventType::Created as i32,
-                                pretty_path: f.pretty_path.display().to_string(),
-                                path: vec![f.path.display().to_string()],
-                                size: Some(f.size),
-                                hash: f.hash,
-                                last_accessed: Some(f.last_accessed.into()),
-                                last_modified: Some(f.last_modified.into()),
-                            });
-                            if self
-                                .client
-                                .as_mut()
-                                .unwrap()
-                                .file_event(tokio_stream::iter(events))
-                                .await
-                                .is_err()
-                            {
-                                warn!("Failed to send file event to gRPC server");
-                                bail!(GrpcClientError::EventSendError());
-                            }
-                        }
-                        Err(e) => {
-                            warn!("Failed to list directory: {:?}", e);
-                            bail!(GrpcClientError::FileInfoError());
-                        }
-                    }
-                } else {
-                    let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
-                        Some(info) => info,
-                        None => bail!(GrpcClientError::FileInfoError()),
-                    };
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+            ModifyKind::Name(notify::event::RenameMode::To) => match event.first() {
+                OnDisk::Tree(files) => {
+                    let events = files.iter().cloned().map(created_event);
+                    self.send_file_events(events, watcher_time(file_event))
+                        .await?;
                 }
-            }
+                OnDisk::File(info) => {
+                    let event = created_event(info.clone());
+                    self.send_file_events(vec![event], watcher_time(file_event))
+                        .await?;
+                }
+                OnDisk::Directory | OnDisk::Gone => bail!(GrpcClientError::FileInfoError()),
+            },
             // The ModifyKind::Name documentation is a bit unprecise, notify::event::RenameMode::From represent a file or folder that was moved out of the scope of the watcher
             // Thus files associated with this event should be deleted from the database
             ModifyKind::Name(notify::event::RenameMode::From) => {
-                if file_event.paths[0].is_dir() {
+                if is_dir {
                     let event = FolderEventRequest {
                         event_type: FileEventType::Deleted as i32,
                         old_path: file_event.paths[0].display().to_string(),
                         new_path: None,
+                        ..Default::default()
                     };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .folder_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    self.send_folder_events(vec![event], watcher_time(file_event))
+                        .await?;
                 } else {
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Deleted as i32,
-                        pretty_path: file_event.paths[0].display().to_string(),
-                        path: vec![file_event.paths[0].display().to_string()],
-                        size: None,
-                        hash: None,
-                        last_accessed: None,
-                        last_modified: None,
-                    };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    let event = deleted_event(&file_event.paths[0]);
+                    self.send_file_events(vec![event], watcher_time(file_event))
+                        .await?;
                 }
             }
             // In this case, the object was actually renamed, so we can use the Moved event type
             ModifyKind::Name(notify::event::RenameMode::Both) => {
-                if file_event.paths[0].is_dir() {
+                if is_dir {
                     let event = FolderEventRequest {
                         event_type: FileEventType::Moved as i32,
                         old_path: file_event.paths[0].display().to_string(),
                         new_path: Some(file_event.paths[1].display().to_string()),
+                        ..Default::default()
                     };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .folder_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    self.send_folder_events(vec![event], watcher_time(file_event))
+                        .await?;
                 } else {
-                    let info = match file_info::create_file_info(&file_event.paths[0].clone()) {
-                        Some(info) => info,
-                        None => bail!(GrpcClientError::FileInfoError()),
-                    };
-                    let event = FileEventRequest {
-                        event_type: FileEventType::Created as i32,
-                        pretty_path: info.pretty_path.display().to_string(),
-                        path: vec![info.path.display().to_string()],
-                        size: Some(info.size),
-                        hash: info.hash,
-                        last_accessed: Some(info.last_accessed.into()),
-                        last_modified: Some(info.last_modified.into()),
+                    let OnDisk::File(info) = event.first() else {
+                        bail!(GrpcClientError::FileInfoError());
                     };
-                    if self
-                        .client
-                        .as_mut()
-                        .unwrap()
-                        .file_event(tokio_stream::iter(vec![event]))
-                        .await
-                        .is_err()
-                    {
-                        warn!("Failed to send file event to gRPC server");
-                        bail!(GrpcClientError::EventSendError());
-                    }
+                    let event = created_event(info.clone());
+                    self.send_file_events(vec![event], watcher_time(file_event))
+                        .await?;
                 }
             }
             _ => (),
@@ -368,30 +443,13 @@ impl GrpcClient {
     async fn handle_remove_events(
         &mut self,
         remove_kind: notify::event::RemoveKind,
-        file_event: DebouncedEvent,
+        file_event: &DebouncedEvent,
     ) -> Result<(), Error> {
         match remove_kind {
             notify::event::RemoveKind::File => {
-                let event = FileEventRequest {
-                    event_type: FileEventType::Deleted as i32,
-                    pretty_path: file_event.paths[0].display().to_string(),
-                    path: vec![file_event.paths[0].display().to_string()],
-                    size: None,
-                    hash: None,
-                    last_accessed: None,
-                    last_modified: None,
-                };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .file_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
+                let event = deleted_event(&file_event.paths[0]);
+                self.send_file_events(vec![event], watcher_time(file_event))
+                    .await?;
 
                 Ok(())
             }
@@ -400,22 +458,475 @@ impl GrpcClient {
                     event_type: FileEventType::Deleted as i32,
                     old_path: file_event.paths[0].display().to_string(),
                     new_path: None,
+                    ..Default::default()
                 };
-                if self
-                    .client
-                    .as_mut()
-                    .unwrap()
-                    .folder_event(tokio_stream::iter(vec![event]))
-                    .await
-                    .is_err()
-                {
-                    warn!("Failed to send file event to gRPC server");
-                    bail!(GrpcClientError::EventSendError());
-                }
+                self.send_folder_events(vec![event], watcher_time(file_event))
+                    .await?;
                 Ok(())
             }
             _ => Ok(()),
         }
     }
     // endregion: --- event handlers
+
+    // region: --- senders
+
+    async fn send_file_events(
+        &mut self,
+        events: impl IntoIterator<Item = FileEventRequest>,
+        event_time: SystemTime,
+    ) -> Result<(), GrpcClientError> {
+        let mut events = events.into_iter().peekable();
+        let mut result = Ok(());
+        while events.peek().is_some() {
+            let mut chunk: Vec<FileEventRequest> = events.by_ref().take(EVENT_CHUNK).collect();
+            self.stamp(&mut chunk, event_time)?;
+            // Once a chunk fails, the rest waits in the outbox
+            if result.is_ok() && self.client.is_some() {
+                result = self.deliver_file_events(chunk).await;
+            }
+        }
+        if result.is_ok() {
+            self.catch_up().await;
+        }
+        result
+    }
+
+    async fn deliver_file_events(
+        &mut self,
+        events: Vec<FileEventRequest>,
+    ) -> Result<(), GrpcClientError> {
+        let mut batch = Batch::new(events);
+        let result = loop {
+            let Some(events) = batch.next().await else {
+                break Ok(());
+            };
+            let Some(client) = self.client.as_mut() else {
+                break Err(GrpcClientError::ClientNotConnected());
+            };
+            let start = Instant::now();
+            let result = client.file_event(tokio_stream::iter(events)).await;
+            if let Err(err) = batch.acknowledge("file_event", start.elapsed(), result) {
+                break Err(err);
+            }
+        };
+        self.outbox.settle(batch.acknowledged, &batch.settled);
+        let result = result.and(batch.finish());
+        self.resend |= result.is_err();
+        result
+    }
+
+    async fn send_folder_events(
+        &mut self,
+        mut events: Vec<FolderEventRequest>,
+        event_time: SystemTime,
+    ) -> Result<(), GrpcClientError> {
+        self.stamp(&mut events, event_time)?;
+        if self.client.is_none() {
+            return Ok(());
+        }
+        let result = self.deliver_folder_events(events).await;
+        if result.is_ok() {
+            self.catch_up().await;
+        }
+        result
+    }
+
+    async fn deliver_folder_events(
+        &mut self,
+        events: Vec<FolderEventRequest>,
+    ) -> Result<(), GrpcClientError> {
+        let mut batch = Batch::new(events);
+        let result = loop {
+            let Some(events) = batch.next().await else {
+                break Ok(());
+            };
+            let Some(client) = self.client.as_mut() else {
+                break Err(GrpcClientError::ClientNotConnected());
+            };
+            let start = Instant::now();
+            let result = client.folder_event(tokio_stream::iter(events)).await;
+            if let Err(err) = batch.acknowledge("folder_event", start.elapsed(), result) {
+                break Err(err);
+            }
+        };
+        self.outbox.settle(batch.acknowledged, &batch.settled);
+        let result = result.and(batch.finish());
+        self.resend |= result.is_err();
+        result
+    }
+
+    // Numbers the events once and keeps them until the Hub has them, retries keep their sequence and key
+    fn stamp<T: HubEvent>(
+        &mut self,
+        events: &mut [T],
+        event_time: SystemTime,
+    ) -> Result<(), GrpcClientError> {
+        let Some(first) = self.outbox.assign(events.len()) else {
+            return Err(GrpcClientError::OutboxFull());
+        };
+        for (sequence, event) in (first..).zip(events.iter_mut()) {
+            event.stamp(sequence, event_time);
+        }
+        self.outbox
+            .keep(events.iter().cloned().map(HubEvent::stored));
+        telemetry::event_sequence(self.outbox.last());
+        Ok(())
+    }
+
+    // endregion: --- senders
+}
+
+// region: --- acknowledgements
+
+trait HubEvent: Clone {
+    fn describe(&self) -> &str;
+    fn sequence(&self) -> u64;
+    fn stamp(&mut self, sequence: u64, event_time: SystemTime);
+    fn stored(self) -> OutboxEvent;
+}
+
+impl HubEvent for FileEventRequest {
+    fn describe(&self) -> &str {
+        &self.pretty_path
+    }
+
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn stored(self) -> OutboxEvent {
+        OutboxEvent::File(self)
+    }
+
+    fn stamp(&mut self, sequence: u64, event_time: SystemTime) {
+        self.sequence = sequence;
+        self.event_time = Some(event_time.into());
+        self.idempotency_key = idempotency_key(
+            &[
+                &self.event_type.to_string(),
+                &self.path.join("\n"),
+                self.hash.as_deref().unwrap_or_default(),
+                &self.size.unwrap_or_default().to_string(),
+            ],
+            event_time,
+        );
+    }
+}
+
+impl HubEvent for FolderEventRequest {
+    fn describe(&self) -> &str {
+        &self.old_path
+    }
+
+    fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    fn stored(self) -> OutboxEvent {
+        OutboxEvent::Folder(self)
+    }
+
+    fn stamp(&mut self, sequence: u64, event_time: SystemTime) {
+        self.sequence = sequence;
+        self.event_time = Some(event_time.into());
+        self.idempotency_key = idempotency_key(
+            &[
+                &self.event_type.to_string(),
+                &self.old_path,
+                self.new_path.as_deref().unwrap_or_default(),
+            ],
+            event_time,
+        );
+    }
+}
+
+// Derived from the change itself, so that the Hub can tell a redelivery from a new change
+fn idempotency_key(parts: &[&str], event_time: SystemTime) -> String {
+    let time = event_time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let key = xxh3_128(format!("{}\0{}", parts.join("\0"), time).as_bytes());
+    format!("{:032x}", key)
+}
+
+/// Events sent in one call, sent again while the Hub rejects some of them as retryable
+struct Batch<T> {
+    pending: Vec<T>,
+    attempts: usize,
+    rejected: usize,
+    /// Highest sequence up to which the Hub holds every event
+    acknowledged: u64,
+    /// Events to drop from the outbox, refused for good or accepted by a Hub that does not acknowledge sequences
+    settled: Vec<u64>,
+}
+
+impl<T: HubEvent> Batch<T> {
+    fn new(events: Vec<T>) -> Self {
+        Self {
+            pending: events,
+            attempts: 0,
+            rejected: 0,
+            acknowledged: 0,
+            settled: Vec::new(),
+        }
+    }
+
+    async fn next(&mut self) -> Option<Vec<T>> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        if self.attempts > 0 {
+            tokio::time::sleep(REJECTION_RETRY_DELAY * self.attempts as u32).await;
+        }
+        Some(self.pending.clone())
+    }
+
+    fn acknowledge(
+        &mut self,
+        method: &'static str,
+        duration: Duration,
+        result: Result<Response<FileInfoEventResponse>, Status>,
+    ) -> Result<(), GrpcClientError> {
+        let sent = std::mem::take(&mut self.pending);
+        let response = match result {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                telemetry::grpc_call(method, sent.len(), duration, false);
+                warn!("Failed to send {} to gRPC server: {}", method, status);
+                return Err(GrpcClientError::EventSendError());
+            }
+        };
+        self.attempts += 1;
+        let acknowledges = response.acknowledged_sequence > 0;
+        if acknowledges {
+            telemetry::sequence_acknowledged(response.acknowledged_sequence);
+            self.acknowledged = self.acknowledged.max(response.acknowledged_sequence);
+        }
+
+        let failed = response.status() == tidybee_events::Status::Error;
+        let mut rejections = response.rejections;
+        if failed && rejections.is_empty() {
+            rejections = (0..sent.len() as u32)
+                .map(|index| EventRejection {
+                    index,
+                    reason: "the Hub failed the whole stream".to_owned(),
+                    retryable: true,
+                })
+                .collect();
+        }
+
+        let count = sent.len();
+        let mut sent: Vec<Option<T>> = sent.into_iter().map(Some).collect();
+        let mut refused = 0;
+        for rejection in rejections {
+            let Some(event) = sent
+                .get_mut(rejection.index as usize)
+                .and_then(Option::take)
+            else {
+                continue;
+            };
+            refused += 1;
+            if rejection.retryable && self.attempts <= REJECTION_RETRIES {
+                debug!(
+                    "The Hub rejected {}, sending it again: {}",
+                    event.describe(),
+                    rejection.reason
+                );
+                self.pending.push(event);
+            } else {
+                warn!(
+                    "The Hub rejected {}: {}",
+                    event.describe(),
+                    rejection.reason
+                );
+                telemetry::event_rejected();
+                self.rejected += 1;
+                // Retryable ones are kept for the next connection
+                if !rejection.retryable {
+                    self.settled.push(event.sequence());
+                }
+            }
+        }
+        if !acknowledges {
+            self.settled
+                .extend(sent.iter().flatten().map(HubEvent::sequence));
+        }
+        telemetry::grpc_call(method, count - refused, duration, true);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), GrpcClientError> {
+        match self.rejected {
+            0 => Ok(()),
+            rejected => Err(GrpcClientError::EventsRejected(rejected)),
+        }
+    }
+}
+
+// endregion: --- acknowledgements
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(path: &str, sequence: u64) -> FileEventRequest {
+        FileEventRequest {
+            pretty_path: path.to_owned(),
+            sequence,
+            ..Default::default()
+        }
+    }
+
+    fn rejecting(rejections: Vec<EventRejection>) -> Response<FileInfoEventResponse> {
+        Response::new(FileInfoEventResponse {
+            status: tidybee_events::Status::Error as i32,
+            rejections,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn rejected_events_are_retried_then_reported() {
+        let mut batch = Batch::new(vec![event("a", 1), event("b", 2), event("c", 3)]);
+        let rejections = vec![
+            EventRejection {
+                index: 0,
+                reason: "invalid hash".to_owned(),
+                retryable: false,
+            },
+            EventRejection {
+                index: 2,
+                reason: "busy".to_owned(),
+                retryable: true,
+            },
+        ];
+        batch
+            .acknowledge("file_event", Duration::ZERO, Ok(rejecting(rejections)))
+            .unwrap();
+        assert_eq!(batch.pending.len(), 1);
+        assert_eq!(batch.pending[0].pretty_path, "c");
+        // The Hub does not acknowledge sequences, what it accepted or refused for good is settled
+        assert_eq!(batch.settled, vec![1, 2]);
+
+        for _ in 0..REJECTION_RETRIES {
+            batch
+                .acknowledge("file_event", Duration::ZERO, Ok(rejecting(Vec::new())))
+                .unwrap();
+        }
+        assert!(batch.pending.is_empty());
+        // Kept in the outbox for the next connection
+        assert_eq!(batch.settled, vec![1, 2]);
+        assert!(matches!(
+            batch.finish(),
+            Err(GrpcClientError::EventsRejected(2))
+        ));
+    }
+
+    // Fails the first stream, then acknowledges every event it holds without a gap
+    #[derive(Default)]
+    struct FlakyHub {
+        failed: std::sync::Mutex<bool>,
+        received: std::sync::Mutex<Vec<u64>>,
+    }
+
+    #[tonic::async_trait]
+    impl tidybee_events::tidy_bee_events_server::TidyBeeEvents for FlakyHub {
+        async fn file_event(
+            &self,
+            request: Request<Streaming<FileEventRequest>>,
+        ) -> Result<Response<FileInfoEventResponse>, Status> {
+            let mut events = request.into_inner();
+            let mut sequences = Vec::new();
+            while let Some(event) = events.message().await? {
+                sequences.push(event.sequence);
+            }
+            if !std::mem::replace(&mut *self.failed.lock().unwrap(), true) {
+                return Err(Status::unavailable("restarting"));
+            }
+            let mut received = self.received.lock().unwrap();
+            received.extend(sequences);
+            received.sort_unstable();
+            received.dedup();
+            let acknowledged = (1..)
+                .zip(received.iter())
+                .take_while(|(n, s)| n == *s)
+                .count();
+            Ok(Response::new(FileInfoEventResponse {
+                acknowledged_sequence: acknowledged as u64,
+                ..Default::default()
+            }))
+        }
+
+        async fn folder_event(
+            &self,
+            _: Request<Streaming<FolderEventRequest>>,
+        ) -> Result<Response<FileInfoEventResponse>, Status> {
+            Err(Status::unimplemented("folder_event"))
+        }
+
+        async fn heartbeat(
+            &self,
+            _: Request<HeartbeatRequest>,
+        ) -> Result<Response<tidybee_events::HeartbeatResponse>, Status> {
+            Err(Status::unimplemented("heartbeat"))
+        }
+
+        type CommandChannelStream = ReceiverStream<Result<HubCommand, Status>>;
+
+        async fn command_channel(
+            &self,
+            _: Request<Streaming<CommandResult>>,
+        ) -> Result<Response<Self::CommandChannelStream>, Status> {
+            Err(Status::unimplemented("command_channel"))
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_events_are_sent_again_once_the_hub_answers() {
+        let hub = Arc::new(FlakyHub::default());
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let server = tonic::transport::Server::builder()
+            .add_service(
+                tidybee_events::tidy_bee_events_server::TidyBeeEventsServer::from_arc(hub.clone()),
+            )
+            .serve(address);
+        tokio::spawn(server);
+
+        let mut config = crate::configuration::Configuration::default()
+            .hub_config
+            .grpc_server;
+        config.host = "127.0.0.1".to_owned();
+        config.protocol = "http".to_owned();
+        config.port = address.port();
+        let mut client = GrpcClient::new(&config).unwrap();
+        let tokens = TokenStore::default();
+        tokens.set(crate::http::token::AccessToken::new(
+            "token".to_owned(),
+            Duration::from_secs(3600),
+        ));
+        client.set_token_store(tokens);
+        for _ in 0..50 {
+            if client.connect().await.is_ok() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let now = SystemTime::now();
+        assert!(client
+            .send_file_events(vec![event("a", 0)], now)
+            .await
+            .is_err());
+        client
+            .send_file_events(vec![event("b", 0)], now)
+            .await
+            .unwrap();
+        assert_eq!(*hub.received.lock().unwrap(), vec![1, 2]);
+        assert!(client.outbox.pending().is_empty());
+    }
 }
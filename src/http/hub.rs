@@ -1,104 +1,285 @@
 use crate::agent_uuid;
 use crate::configuration::HubConfig;
-use crate::error::HubError::*;
+use crate::error::HubError::{self, *};
+use crate::http::compatibility::{self, Compatibility, HubRequirements};
+use crate::http::connection::{Backoff, HubConnectionStatus, SharedHubConnectionStatus};
 use crate::http::grpc::GrpcClient;
+use crate::http::registration::{AgentRegistration, AgentRegistrationResponse};
+use crate::http::tls;
+use crate::http::token::{AccessToken, TokenClient, TokenStore};
+use crate::identity::AgentIdentity;
+use crate::outbox::Outbox;
+use crate::state::StateDir;
+use crate::telemetry;
 use anyhow::{bail, Error};
-use gethostname::gethostname;
 use reqwest::header::CONTENT_TYPE;
 use reqwest::Client;
-use tracing::{error, info};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
 
 pub struct Hub {
     config: HubConfig,
+    registration: AgentRegistration,
+    identity: AgentIdentity,
+    state: StateDir,
+    tokens: TokenStore,
+    token_refresh: Option<JoinHandle<()>>,
+    backoff: Backoff,
+    status: SharedHubConnectionStatus,
     http_client: Client,
+    minimal_hub_version: String,
+    compatibility: Compatibility,
     pub grpc_client: GrpcClient,
 }
 
 impl Hub {
-    pub fn new(hub_config: HubConfig) -> Result<Self, Error> {
-        let http_client: Client = Client::new();
-        let grpc_client = match GrpcClient::new(&hub_config.grpc_server) {
+    pub fn new(
+        hub_config: HubConfig,
+        registration: AgentRegistration,
+        identity: AgentIdentity,
+        state: StateDir,
+        minimal_hub_version: String,
+    ) -> Result<Self, Error> {
+        let http_client: Client = match hub_config.protocol.as_str() {
+            "https" => Client::builder()
+                .use_preconfigured_tls(tls::client_config(&hub_config.tls, &[b"h2", b"http/1.1"])?)
+                .build()?,
+            _ => Client::new(),
+        };
+        let tokens = TokenStore::default();
+        let mut grpc_client = match GrpcClient::new(&hub_config.grpc_server) {
             Ok(client) => client,
             Err(e) => {
                 bail!(HubClientCreationFailed(e.to_string()))
             }
         };
+        grpc_client.set_token_store(tokens.clone());
         Ok(Self {
+            backoff: Backoff::from_config(&hub_config),
+            status: Arc::new(Mutex::new(HubConnectionStatus::default())),
             config: hub_config,
+            registration,
+            identity,
+            state,
+            tokens,
+            token_refresh: None,
             http_client,
+            minimal_hub_version,
+            compatibility: Compatibility::default(),
             grpc_client,
         })
     }
 
-    pub async fn connect(&mut self) -> Result<String, Error> {
-        let agent_uuid = agent_uuid::get_uuid();
-        let base_url = format!(
+    pub fn status(&self) -> SharedHubConnectionStatus {
+        self.status.clone()
+    }
+
+    /// Reports to an existing status, so that a client replacing another one keeps the API up to date
+    pub fn share_status(&mut self, status: SharedHubConnectionStatus) {
+        self.status = status;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.grpc_client.client.is_some()
+    }
+
+    /// Whether the agent may use `feature`, as negotiated on connecting, never before
+    pub fn allows(&self, feature: &str) -> bool {
+        self.is_connected() && self.compatibility.allows(feature)
+    }
+
+    /// Loads the events waiting for the Hub, only one client of the agent may hold them
+    pub fn load_outbox(&mut self) {
+        self.grpc_client.set_outbox(Outbox::load(&self.state));
+    }
+
+    /// Carries on the event numbering and the outbox of the client this one replaces
+    pub fn take_over(&mut self, previous: &mut Hub) {
+        self.grpc_client.take_outbox(&mut previous.grpc_client);
+    }
+
+    fn base_url(&self) -> String {
+        format!(
             "{}://{}:{}",
             self.config.protocol, self.config.host, self.config.port
-        );
+        )
+    }
 
-        let url = match agent_uuid {
-            Ok(uuid) => {
-                format!("{}{}/{}", base_url, self.config.auth_path, uuid)
-            }
-            Err(_) => {
-                format!("{}{}", base_url, self.config.auth_path)
+    // Evaluated on every attempt so that retries reuse the id handed out by the Hub
+    fn registration_url(&self) -> String {
+        match agent_uuid::get_uuid(&self.state) {
+            Ok(uuid) => format!("{}{}/{}", self.base_url(), self.config.auth_path, uuid),
+            Err(_) => format!("{}{}", self.base_url(), self.config.auth_path),
+        }
+    }
+
+    pub async fn connect(&mut self) -> Result<String, Error> {
+        self.registration.update();
+        self.status.lock().unwrap().reset_attempts();
+
+        let (agent_id, requirements, token_client, token) = loop {
+            self.status.lock().unwrap().attempt();
+            let err = match self.authenticate().await {
+                Ok(authenticated) => break authenticated,
+                Err(err) => err,
+            };
+            if !err.is_retryable() {
+                error!("The Hub refused the agent authentication: {}", err);
+                self.status.lock().unwrap().failure(&err, None);
+                bail!(err)
             }
+            self.retry_later(&err).await?;
         };
 
-        let agent_connection_data = format!(
-            r#"
-            {{
-                "Metadata": {{}},
-                "ConnectionModel": {{
-                    "address": "{}",
-                    "port": "8111"
-                }}
-            }}"#,
-            gethostname().to_str().unwrap(),
+        info!(
+            "Successfully connected the agent to the Hub with id: {}",
+            agent_id
+        );
+        self.compatibility = compatibility::negotiate(&requirements, &self.minimal_hub_version);
+        self.status
+            .lock()
+            .unwrap()
+            .negotiated(self.compatibility.clone());
+        match &self.compatibility {
+            Compatibility::Compatible => {}
+            Compatibility::Degraded { disabled_features } => warn!(
+                "The Hub does not support {}, leaving them off",
+                disabled_features.join(", ")
+            ),
+            Compatibility::Incompatible { reason } => {
+                error!("Nothing will be sent to the Hub: {}", reason);
+                return Ok(agent_id);
+            }
+        }
+        self.tokens.set(token);
+        if let Some(token_refresh) = self.token_refresh.take() {
+            token_refresh.abort();
+        }
+        self.token_refresh = Some(
+            token_client.spawn_refresh(self.tokens.clone(), Backoff::from_config(&self.config)),
         );
 
-        let mut tries = 0;
-        while tries < self.config.connection_attempt_limit {
-            let response = self
-                .http_client
-                .post(&url)
-                .header(CONTENT_TYPE, "application/json")
-                .json(&agent_connection_data)
-                .send()
-                .await;
-            match response {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        return match response.text().await {
-                            Ok(mut text) => {
-                                text = text.trim_matches('"').to_owned();
-                                info!(
-                                    "Successfully connected the agent to the Hub with id: {}",
-                                    text
-                                );
-                                if let Err(err) = agent_uuid::set_uuid(text.clone()) {
-                                    error!("{err}");
-                                }
-                                self.grpc_client.set_agent_uuid(&text);
-                                while self.grpc_client.connect().await.is_err() {
-                                    info!("Failed to connect to the gRPC server, retrying in 5 seconds");
-                                    tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-                                }
-                                Ok(text)
-                            }
-                            Err(err) => {
-                                bail!(HttpError(err))
-                            }
-                        };
-                    }
-                }
-                Err(e) => {
-                    bail!(UnExpectedError(e.to_string()))
-                }
+        self.backoff.reset();
+        self.status.lock().unwrap().reset_attempts();
+        loop {
+            self.status.lock().unwrap().attempt();
+            match self.grpc_client.connect().await {
+                Ok(()) => break,
+                Err(err) => self.retry_later(&err).await?,
             }
-            tries += 1;
         }
-        bail!(MaximumAttemptsReached())
+
+        self.backoff.reset();
+        self.status.lock().unwrap().connected();
+        telemetry::hub_connected();
+        Ok(agent_id)
+    }
+
+    /// The delay before connecting again once `connect` gave up, carrying on the backoff of its attempts
+    pub fn retry_delay(&mut self) -> Duration {
+        self.backoff.next_delay()
+    }
+
+    // Registers the agent and its public key, then trades a signed challenge for a token
+    async fn authenticate(
+        &self,
+    ) -> Result<(String, HubRequirements, TokenClient, AccessToken), HubError> {
+        let response = self.register(&self.registration_url()).await?;
+        let agent_id = response.agent_id().to_owned();
+        if let Err(err) = agent_uuid::set_uuid(&self.state, &agent_id) {
+            error!("{err}");
+        }
+
+        let token_client = self.token_client(&agent_id);
+        let token = token_client.fetch().await?;
+        Ok((agent_id, response.requirements(), token_client, token))
+    }
+
+    fn token_client(&self, agent_id: &str) -> TokenClient {
+        TokenClient::new(
+            self.http_client.clone(),
+            &self.base_url(),
+            &self.config.challenge_path,
+            &self.config.token_path,
+            agent_id,
+            self.identity.clone(),
+        )
+    }
+
+    /// Tells the Hub to forget this agent, then drops the stored id
+    pub async fn unregister(&mut self) -> Result<String, Error> {
+        let agent_id = agent_uuid::get_uuid(&self.state)?;
+        let token = self.token_client(&agent_id).fetch().await?;
+        let url = format!(
+            "{}{}",
+            self.base_url(),
+            self.config.disconnect_path.replace("{agent_id}", &agent_id)
+        );
+
+        let response = self
+            .http_client
+            .post(url)
+            .bearer_auth(token.secret())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            bail!(UnexpectedStatus(response.status()))
+        }
+
+        agent_uuid::remove_uuid(&self.state)?;
+        info!("Unregistered agent {} from the Hub", agent_id);
+        Ok(agent_id)
+    }
+
+    async fn register(&self, url: &str) -> Result<AgentRegistrationResponse, HubError> {
+        let response = self
+            .http_client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&self.registration)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(UnexpectedStatus(response.status()));
+        }
+        Ok(response.json::<AgentRegistrationResponse>().await?)
+    }
+
+    // Waits for the next backoff delay, or fails once the attempt limit is reached (0 means no limit)
+    async fn retry_later(&mut self, err: &(dyn Display + Sync)) -> Result<(), HubError> {
+        let attempts = self.status.lock().unwrap().attempts;
+        let limit = self.config.connection_attempt_limit;
+        if limit != 0 && attempts >= limit {
+            error!("Error connecting to the hub: {}, giving up", err);
+            self.status.lock().unwrap().failure(err, None);
+            return Err(MaximumAttemptsReached());
+        }
+
+        let delay = self.backoff.next_delay();
+        let limit = match limit {
+            0 => String::new(),
+            limit => format!("/{}", limit),
+        };
+        warn!(
+            "Error connecting to the hub: {}, retrying in {:?} (attempt {}{})",
+            err, delay, attempts, limit
+        );
+        self.status.lock().unwrap().failure(err, Some(delay));
+        telemetry::hub_reconnect();
+        sleep(delay).await;
+        Ok(())
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        if let Some(token_refresh) = self.token_refresh.take() {
+            token_refresh.abort();
+        }
     }
 }
@@ -1,3 +1,9 @@
-mod grpc;
+pub mod auth;
+pub mod compatibility;
+pub mod connection;
+pub mod grpc;
 pub mod hub;
+pub mod registration;
 pub mod routes;
+pub mod tls;
+pub mod token;
@@ -0,0 +1,207 @@
+use crate::agent_data::AgentData;
+use crate::configuration::Configuration;
+use crate::http::compatibility::{
+    HubRequirements, AGENT_VERSION, COMMAND_CHANNEL, FILE_EVENTS, HEARTBEAT, PROTOCOL_VERSION,
+};
+use gethostname::gethostname;
+use serde_derive::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// Features of the agent the Hub can rely on when talking to it
+pub const CAPABILITIES: [&str; 5] = [
+    FILE_EVENTS,
+    "folder_events",
+    "xxh3_128_hash",
+    HEARTBEAT,
+    COMMAND_CHANNEL,
+];
+
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct ConnectionModel {
+    pub address: String,
+    pub port: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct AgentMetadata {
+    pub version: String,
+    pub protocol_version: u32,
+    pub os: String,
+    pub arch: String,
+    pub capabilities: Vec<String>,
+    pub public_key: String,
+    // Provides machine_name, process_id, uptime and watched_directories
+    #[serde(flatten)]
+    pub agent_data: AgentData,
+}
+
+/// Body sent to the Hub `auth_path` when the agent (re)connects
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct AgentRegistration {
+    pub metadata: AgentMetadata,
+    pub connection_model: ConnectionModel,
+}
+
+/// The Hub answers either with the bare agent id as a JSON string or with an object holding it
+/// along with its requirements
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum AgentRegistrationResponse {
+    Id(String),
+    Agent {
+        #[serde(alias = "id", alias = "Id", alias = "agentId", alias = "AgentId")]
+        agent_id: String,
+        #[serde(flatten)]
+        requirements: HubRequirements,
+    },
+}
+
+impl AgentRegistrationResponse {
+    pub fn agent_id(&self) -> &str {
+        match self {
+            Self::Id(id) => id,
+            Self::Agent { agent_id, .. } => agent_id,
+        }
+    }
+
+    pub fn requirements(&self) -> HubRequirements {
+        match self {
+            Self::Id(_) => HubRequirements::default(),
+            Self::Agent { requirements, .. } => requirements.clone(),
+        }
+    }
+}
+
+impl AgentRegistration {
+    pub fn build(config: &Configuration, public_key: String) -> Self {
+        let agent_data = AgentData::build(
+            config.agent_data.latest_version.clone(),
+            config.agent_data.minimal_version.clone(),
+            config.filesystem_interface_config.dir.clone(),
+        );
+
+        Self {
+            metadata: AgentMetadata {
+                version: AGENT_VERSION.to_owned(),
+                protocol_version: PROTOCOL_VERSION,
+                os: std::env::consts::OS.to_owned(),
+                arch: std::env::consts::ARCH.to_owned(),
+                capabilities: CAPABILITIES.iter().map(|c| (*c).to_owned()).collect(),
+                public_key,
+                agent_data,
+            },
+            connection_model: connection_model(&config.server_config.address),
+        }
+    }
+
+    pub fn update(&mut self) {
+        self.metadata.agent_data.update();
+    }
+}
+
+fn hostname() -> String {
+    gethostname().to_string_lossy().into_owned()
+}
+
+// The Hub needs an address it can reach us on, so wildcard binds are advertised with the hostname
+fn connection_model(server_address: &str) -> ConnectionModel {
+    if let Ok(addr) = server_address.parse::<SocketAddr>() {
+        let address = if addr.ip().is_unspecified() {
+            hostname()
+        } else {
+            addr.ip().to_string()
+        };
+        return ConnectionModel {
+            address,
+            port: addr.port().to_string(),
+        };
+    }
+
+    match server_address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => ConnectionModel {
+            address: host.to_owned(),
+            port: port.to_owned(),
+        },
+        _ => {
+            warn!(
+                "Could not extract host and port from server address: {}",
+                server_address
+            );
+            ConnectionModel {
+                address: hostname(),
+                port: String::new(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_address_uses_hostname() {
+        let model = connection_model("0.0.0.0:9000");
+        assert_eq!(model.address, hostname());
+        assert_eq!(model.port, "9000");
+    }
+
+    #[test]
+    fn explicit_address_is_kept() {
+        assert_eq!(
+            connection_model("127.0.0.1:8111"),
+            ConnectionModel {
+                address: "127.0.0.1".to_owned(),
+                port: "8111".to_owned(),
+            }
+        );
+        assert_eq!(
+            connection_model("agent.local:8112"),
+            ConnectionModel {
+                address: "agent.local".to_owned(),
+                port: "8112".to_owned(),
+            }
+        );
+    }
+
+    #[test]
+    fn registration_is_a_json_object() {
+        let registration =
+            AgentRegistration::build(&Configuration::default(), "public-key".to_owned());
+        let value = serde_json::to_value(registration).unwrap();
+
+        assert!(value.is_object());
+        assert_eq!(value["ConnectionModel"]["port"], "8111");
+        assert_eq!(value["Metadata"]["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(value["Metadata"]["protocol_version"], PROTOCOL_VERSION);
+        assert_eq!(value["Metadata"]["public_key"], "public-key");
+        assert!(value["Metadata"]["machine_name"].is_string());
+        assert!(value["Metadata"]["watched_directories"].is_array());
+    }
+
+    #[test]
+    fn response_accepts_string_and_object() {
+        let bare: AgentRegistrationResponse = serde_json::from_str(r#""abc""#).unwrap();
+        let object: AgentRegistrationResponse = serde_json::from_str(r#"{"id": "abc"}"#).unwrap();
+
+        assert_eq!(bare.agent_id(), "abc");
+        assert_eq!(object.agent_id(), "abc");
+        assert_eq!(bare.requirements(), HubRequirements::default());
+    }
+
+    #[test]
+    fn response_carries_hub_requirements() {
+        let response: AgentRegistrationResponse = serde_json::from_str(
+            r#"{"agentId": "abc", "hubVersion": "1.4.0", "minimalAgentVersion": "0.1.0", "features": ["file_events"]}"#,
+        )
+        .unwrap();
+        let requirements = response.requirements();
+
+        assert_eq!(response.agent_id(), "abc");
+        assert_eq!(requirements.hub_version.as_deref(), Some("1.4.0"));
+        assert_eq!(requirements.minimal_agent_version.as_deref(), Some("0.1.0"));
+        assert_eq!(requirements.features, Some(vec!["file_events".to_owned()]));
+    }
+}
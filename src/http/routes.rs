@@ -1,29 +1,94 @@
-use crate::agent_data::AgentData;
+use crate::agent_data::{AgentData, RootStats, SyncStats};
 use crate::configuration::Configuration;
-use axum::extract::State;
+use crate::event_stream::{self, EventFilter, EventSender, FileEvent, FileEventKind};
+use crate::file_index::{DuplicateCluster, FileFilter, FileIndex};
+use crate::file_info::FileInfo;
+use crate::file_lister::SharedScanProgress;
+use crate::file_type::FileCategory;
+use crate::health::{is_ready, AgentHealth, SharedAgentHealth};
+use crate::http::connection::{HubConnectionStatus, SharedHubConnectionStatus};
+use crate::similarity::{self, NearDuplicateCluster};
+use crate::telemetry;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
 use axum::Json;
-use serde_derive::Serialize;
+use futures::{Stream, StreamExt};
+use metrics_exporter_prometheus::PrometheusHandle;
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use sysinfo::System;
+use tokio::sync::watch;
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
 #[derive(Clone)]
 pub struct AgentDataState {
     pub agent_data: Arc<Mutex<AgentData>>,
+    pub config: watch::Receiver<Configuration>,
+    pub index: FileIndex,
+    pub scan_progress: SharedScanProgress,
+    pub hub_status: SharedHubConnectionStatus,
+    pub system: Arc<Mutex<System>>,
+}
+
+#[derive(Clone)]
+pub struct HubStatusState {
+    pub status: SharedHubConnectionStatus,
 }
 
 #[derive(Clone)]
 pub struct GlobalConfigState {
-    pub config: Configuration,
+    pub config: watch::Receiver<Configuration>,
 }
 
-#[derive(Serialize)]
-pub struct Greeting {
-    message: String,
+#[derive(Clone)]
+pub struct FilesState {
+    pub index: FileIndex,
+    pub config: watch::Receiver<Configuration>,
 }
 
 pub async fn get_status(State(agent_data): State<AgentDataState>) -> Json<AgentData> {
     let mut agent_data_cloned = agent_data.agent_data.lock().unwrap().clone();
+    let directories = agent_data
+        .config
+        .borrow()
+        .filesystem_interface_config
+        .dir
+        .clone();
 
     agent_data_cloned.update();
+    agent_data_cloned.set_roots(
+        directories
+            .iter()
+            .map(|directory| {
+                let (files, bytes) = directory
+                    .canonicalize()
+                    .map_or((0, 0), |root| agent_data.index.totals(&root));
+                RootStats {
+                    path: directory.clone(),
+                    files,
+                    bytes,
+                }
+            })
+            .collect(),
+    );
+    agent_data_cloned.set_watched_directories(directories);
+    agent_data_cloned.set_initial_scan(agent_data.scan_progress.lock().unwrap().clone());
+    agent_data_cloned.set_sync(SyncStats {
+        last_event_at: telemetry::last_watcher_event(),
+        last_event_sent_at: telemetry::last_event_sent(),
+        pending_events: telemetry::pending_events(),
+        last_sequence: telemetry::last_sequence(),
+        acknowledged_sequence: telemetry::acknowledged_sequence(),
+    });
+    agent_data_cloned.set_hub(agent_data.hub_status.lock().unwrap().clone());
+    agent_data_cloned.refresh_process(&mut agent_data.system.lock().unwrap());
     Json(agent_data_cloned)
 }
 
@@ -35,8 +100,507 @@ pub struct GetConfigResponseType {
 pub async fn get_config(
     State(global_config): State<GlobalConfigState>,
 ) -> Json<GetConfigResponseType> {
-    let configuration = global_config.config;
+    let configuration = global_config.config.borrow().redacted();
     let response = GetConfigResponseType { configuration };
 
     Json(response)
 }
+
+pub async fn get_hub_status(State(hub_status): State<HubStatusState>) -> Json<HubConnectionStatus> {
+    let status = hub_status.status.lock().unwrap().clone();
+
+    Json(status)
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortKey {
+    #[default]
+    Path,
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+#[derive(Deserialize)]
+pub struct FilesQuery {
+    root: Option<PathBuf>,
+    name: Option<String>,
+    ext: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    modified_after: Option<String>,
+    modified_before: Option<String>,
+    hash: Option<String>,
+    category: Option<FileCategory>,
+    mismatch: Option<bool>,
+    #[serde(default)]
+    sort: SortKey,
+    #[serde(default)]
+    order: SortOrder,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct FilesPage {
+    total: usize,
+    page: usize,
+    per_page: usize,
+    files: Vec<FileInfo>,
+}
+
+type ApiError = (StatusCode, String);
+
+/// Lists the indexed files matching the query, a page at a time
+pub async fn get_files(
+    State(files): State<FilesState>,
+    Query(query): Query<FilesQuery>,
+) -> Result<Json<FilesPage>, ApiError> {
+    let root = match &query.root {
+        Some(root) => Some(watched_root(&files.config, root)?),
+        None => None,
+    };
+    let filter = FileFilter {
+        root,
+        name: query.name,
+        extension: query.ext,
+        min_size: query.min_size,
+        max_size: query.max_size,
+        modified_after: parse_time("modified_after", query.modified_after)?,
+        modified_before: parse_time("modified_before", query.modified_before)?,
+        hash: query.hash,
+        category: query.category,
+        type_mismatch: query.mismatch,
+    };
+
+    let mut matching = files.index.filter(&filter);
+    match query.sort {
+        // The index is already ordered by path
+        SortKey::Path => {}
+        SortKey::Name => matching.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name())),
+        SortKey::Size => matching.sort_by_key(|file| file.size),
+        SortKey::Modified => matching.sort_by_key(|file| file.last_modified),
+    }
+    if let SortOrder::Desc = query.order {
+        matching.reverse();
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let total = matching.len();
+    let files = matching
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(Json(FilesPage {
+        total,
+        page,
+        per_page,
+        files,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct DuplicatesQuery {
+    root: Option<PathBuf>,
+    min_size: Option<u64>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicatesPage {
+    total: usize,
+    wasted_bytes: u64,
+    page: usize,
+    per_page: usize,
+    clusters: Vec<DuplicateCluster>,
+}
+
+/// Lists the groups of indexed files with the same content, the most wasteful first
+pub async fn get_duplicates(
+    State(files): State<FilesState>,
+    Query(query): Query<DuplicatesQuery>,
+) -> Result<Json<DuplicatesPage>, ApiError> {
+    let mut clusters = files.index.duplicates(query.min_size.unwrap_or(0));
+    if let Some(root) = &query.root {
+        let root = watched_root(&files.config, root)?;
+        clusters.retain(|cluster| cluster.files.iter().any(|file| file.starts_with(&root)));
+    }
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let total = clusters.len();
+    let wasted_bytes = clusters.iter().map(|cluster| cluster.wasted_bytes).sum();
+    let clusters = clusters
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(Json(DuplicatesPage {
+        total,
+        wasted_bytes,
+        page,
+        per_page,
+        clusters,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    root: Option<PathBuf>,
+    threshold: Option<f64>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarPage {
+    total: usize,
+    threshold: f64,
+    page: usize,
+    per_page: usize,
+    clusters: Vec<NearDuplicateCluster>,
+}
+
+/// Lists the groups of indexed text files and documents with close contents, the biggest first
+pub async fn get_similar(
+    State(files): State<FilesState>,
+    Query(query): Query<SimilarQuery>,
+) -> Result<Json<SimilarPage>, ApiError> {
+    let similarity_config = files.config.borrow().similarity_config.clone();
+    if !similarity_config.enabled {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Near-duplicate detection is disabled".to_owned(),
+        ));
+    }
+    let threshold = query.threshold.unwrap_or(similarity_config.threshold);
+    if !(similarity::MIN_THRESHOLD..=1.0).contains(&threshold) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "threshold must be between {} and 1",
+                similarity::MIN_THRESHOLD
+            ),
+        ));
+    }
+
+    let clusters = near_duplicates(
+        &files,
+        files.index.fingerprints(),
+        query.root.as_ref(),
+        similarity::max_distance(threshold),
+    )
+    .await?;
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let total = clusters.len();
+    let clusters = clusters
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(Json(SimilarPage {
+        total,
+        threshold,
+        page,
+        per_page,
+        clusters,
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct SimilarImagesQuery {
+    root: Option<PathBuf>,
+    max_distance: Option<u32>,
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct SimilarImagesPage {
+    total: usize,
+    max_distance: u32,
+    page: usize,
+    per_page: usize,
+    clusters: Vec<NearDuplicateCluster>,
+}
+
+/// Lists the groups of indexed images that look alike, the biggest first
+pub async fn get_similar_images(
+    State(files): State<FilesState>,
+    Query(query): Query<SimilarImagesQuery>,
+) -> Result<Json<SimilarImagesPage>, ApiError> {
+    let similarity_config = files.config.borrow().similarity_config.clone();
+    if !similarity_config.images {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Image hashing is disabled".to_owned(),
+        ));
+    }
+    let max_distance = query
+        .max_distance
+        .unwrap_or(similarity_config.image_max_distance);
+    if max_distance > similarity::MAX_IMAGE_DISTANCE {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "max_distance must be at most {}",
+                similarity::MAX_IMAGE_DISTANCE
+            ),
+        ));
+    }
+
+    let clusters = near_duplicates(
+        &files,
+        files.index.image_hashes(),
+        query.root.as_ref(),
+        max_distance,
+    )
+    .await?;
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query
+        .per_page
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let total = clusters.len();
+    let clusters = clusters
+        .into_iter()
+        .skip((page - 1).saturating_mul(per_page))
+        .take(per_page)
+        .collect();
+    Ok(Json(SimilarImagesPage {
+        total,
+        max_distance,
+        page,
+        per_page,
+        clusters,
+    }))
+}
+
+async fn near_duplicates(
+    files: &FilesState,
+    mut hashes: Vec<(PathBuf, u64, u64)>,
+    root: Option<&PathBuf>,
+    max_distance: u32,
+) -> Result<Vec<NearDuplicateCluster>, ApiError> {
+    if let Some(root) = root {
+        let root = watched_root(&files.config, root)?;
+        hashes.retain(|(path, _, _)| path.starts_with(&root));
+    }
+    // Comparing the hashes takes a while on big indexes
+    tokio::task::spawn_blocking(move || similarity::clusters(&hashes, max_distance))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct FileQuery {
+    path: PathBuf,
+}
+
+/// Returns the indexed information of a single file
+pub async fn get_file(
+    State(files): State<FilesState>,
+    Query(query): Query<FileQuery>,
+) -> Result<Json<FileInfo>, ApiError> {
+    let not_found = || {
+        (
+            StatusCode::NOT_FOUND,
+            format!("{} is not indexed", query.path.display()),
+        )
+    };
+    let path = query.path.canonicalize().map_err(|_| not_found())?;
+    files.index.get(&path).map(Json).ok_or_else(not_found)
+}
+
+// Only the watched directories can be listed, as given in the configuration or canonicalized
+fn watched_root(
+    config: &watch::Receiver<Configuration>,
+    root: &PathBuf,
+) -> Result<PathBuf, ApiError> {
+    let canonical_root = root.canonicalize().ok();
+    config
+        .borrow()
+        .filesystem_interface_config
+        .dir
+        .iter()
+        .filter_map(|dir| {
+            let canonical_dir = dir.canonicalize().ok()?;
+            (dir == root || Some(&canonical_dir) == canonical_root.as_ref())
+                .then_some(canonical_dir)
+        })
+        .next()
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("{} is not a watched directory", root.display()),
+            )
+        })
+}
+
+fn parse_time(name: &str, value: Option<String>) -> Result<Option<SystemTime>, ApiError> {
+    value
+        .map(|value| {
+            humantime::parse_rfc3339_weak(&value).map_err(|err| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("{name} must be an RFC 3339 date: {err}"),
+                )
+            })
+        })
+        .transpose()
+}
+
+#[derive(Clone)]
+pub struct EventsState {
+    pub events: EventSender,
+    pub config: watch::Receiver<Configuration>,
+}
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    root: Option<PathBuf>,
+    /// Comma separated kinds, such as `created,removed`
+    kind: Option<String>,
+    prefix: Option<PathBuf>,
+}
+
+/// Streams the file events as they happen, over a WebSocket when asked for an upgrade and as Server-Sent Events otherwise
+pub async fn get_events(
+    State(events): State<EventsState>,
+    Query(query): Query<EventsQuery>,
+    websocket: Option<WebSocketUpgrade>,
+) -> Result<Response, ApiError> {
+    let root = match &query.root {
+        Some(root) => Some(watched_root(&events.config, root)?),
+        None => None,
+    };
+    let kinds = match &query.kind {
+        Some(kinds) => kinds
+            .split(',')
+            .map(|kind| {
+                serde_json::from_value(serde_json::Value::String(kind.trim().to_owned())).map_err(
+                    |_| {
+                        (
+                            StatusCode::BAD_REQUEST,
+                            format!("Unknown event kind {kind}, expected created, modified, renamed or removed"),
+                        )
+                    },
+                )
+            })
+            .collect::<Result<Vec<FileEventKind>, ApiError>>()?,
+        None => Vec::new(),
+    };
+    let filter = EventFilter {
+        root,
+        kinds,
+        prefix: query.prefix,
+    };
+    let stream = event_stream::subscribe(&events.events, filter);
+
+    Ok(match websocket {
+        Some(websocket) => websocket
+            .on_upgrade(|socket| forward_events(socket, stream))
+            .into_response(),
+        None => Sse::new(stream.map(|event| SseEvent::default().json_data(event)))
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+    })
+}
+
+async fn forward_events(mut socket: WebSocket, events: impl Stream<Item = FileEvent>) {
+    let mut events = Box::pin(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else { break };
+                let Ok(text) = serde_json::to_string(&event) else { continue };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            // Messages from the client are ignored, the stream ends when it goes away
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsState {
+    pub handle: Option<PrometheusHandle>,
+}
+
+/// Renders the metrics in the Prometheus text format
+pub async fn get_metrics(State(metrics): State<MetricsState>) -> Result<Response, ApiError> {
+    match metrics.handle {
+        Some(handle) => Ok((
+            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+            handle.render(),
+        )
+            .into_response()),
+        None => Err((StatusCode::NOT_FOUND, "Metrics are disabled".to_owned())),
+    }
+}
+
+#[derive(Clone)]
+pub struct HealthState {
+    pub health: SharedAgentHealth,
+    pub hub_status: SharedHubConnectionStatus,
+}
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+}
+
+/// Answers as long as the process serves requests
+pub async fn get_health() -> Json<HealthResponse> {
+    Json(HealthResponse { status: "alive" })
+}
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    ready: bool,
+    #[serde(flatten)]
+    health: AgentHealth,
+    hub: HubConnectionStatus,
+}
+
+/// Tells whether the agent watches, has scanned and is connected, with 503 when it is not ready
+pub async fn get_ready(State(state): State<HealthState>) -> (StatusCode, Json<ReadyResponse>) {
+    let health = state.health.lock().unwrap().clone();
+    let hub = state.hub_status.lock().unwrap().clone();
+    let ready = is_ready(&health, &hub);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadyResponse { ready, health, hub }))
+}
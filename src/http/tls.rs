@@ -0,0 +1,226 @@
+use crate::configuration::TlsConfig;
+use crate::error::HubError::{self, TlsConfigError};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio_rustls::{client::TlsStream, TlsConnector};
+use tonic::transport::Uri;
+use tower::service_fn;
+
+/// Checks the chain against the configured roots, optionally under another name, then the pins
+struct HubCertVerifier {
+    inner: WebPkiVerifier,
+    server_name: Option<ServerName>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for HubCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let server_name = self.server_name.as_ref().unwrap_or(server_name);
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+
+        if !self.pins.is_empty() {
+            let fingerprint: [u8; 32] = Sha256::digest(&end_entity.0).into();
+            if !self.pins.contains(&fingerprint) {
+                return Err(rustls::Error::General(
+                    "Hub certificate does not match any pinned fingerprint".to_owned(),
+                ));
+            }
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, HubError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| TlsConfigError(format!("{}: {}", path.display(), err)))
+}
+
+fn root_store(ca_cert: Option<&Path>) -> Result<RootCertStore, HubError> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            let certs = rustls_pemfile::certs(&mut open(path)?)
+                .map_err(|err| TlsConfigError(format!("{}: {}", path.display(), err)))?;
+            let (added, _) = roots.add_parsable_certificates(&certs);
+            if added == 0 {
+                return Err(TlsConfigError(format!(
+                    "{}: no usable CA certificate",
+                    path.display()
+                )));
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        })),
+    }
+    Ok(roots)
+}
+
+fn client_identity(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<(Vec<Certificate>, PrivateKey), HubError> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut open(cert_path)?)
+        .map_err(|err| TlsConfigError(format!("{}: {}", cert_path.display(), err)))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsConfigError(format!(
+            "{}: no client certificate",
+            cert_path.display()
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut open(key_path)?)
+        .map_err(|err| TlsConfigError(format!("{}: {}", key_path.display(), err)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| TlsConfigError(format!("{}: no private key", key_path.display())))?;
+
+    Ok((certs, key))
+}
+
+/// Parses a SHA-256 fingerprint written as `sha256/<base64>` or as hex, colons allowed
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], HubError> {
+    let bytes = match pin.strip_prefix("sha256/") {
+        Some(encoded) => BASE64.decode(encoded).ok(),
+        None => {
+            let hex: String = pin.chars().filter(|c| *c != ':').collect();
+            hex.as_bytes()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<u8>>>()
+        }
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| TlsConfigError(format!("Invalid SHA-256 pin: {}", pin)))
+}
+
+/// Builds the rustls configuration shared by the Hub HTTP and gRPC clients
+pub fn client_config(tls: &TlsConfig, alpn_protocols: &[&[u8]]) -> Result<ClientConfig, HubError> {
+    let server_name = tls
+        .server_name
+        .as_deref()
+        .map(ServerName::try_from)
+        .transpose()
+        .map_err(|err| TlsConfigError(format!("Invalid server name: {}", err)))?;
+    let verifier = HubCertVerifier {
+        inner: WebPkiVerifier::new(root_store(tls.ca_cert.as_deref())?, None),
+        server_name,
+        pins: tls
+            .pinned_sha256
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<_, _>>()?,
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier));
+    let mut config = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            let (certs, key) = client_identity(cert, key)?;
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|err| TlsConfigError(err.to_string()))?
+        }
+        (None, None) => builder.with_no_client_auth(),
+        _ => {
+            return Err(TlsConfigError(
+                "client_cert and client_key must be set together".to_owned(),
+            ))
+        }
+    };
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// tonic connector opening a TLS session, using the `server_name` override for SNI when set
+pub fn grpc_connector(
+    config: Arc<ClientConfig>,
+    server_name: Option<String>,
+) -> impl tower::Service<
+    Uri,
+    Response = TlsStream<TcpStream>,
+    Error = io::Error,
+    Future = impl std::future::Future<Output = Result<TlsStream<TcpStream>, io::Error>> + Send,
+> + Clone {
+    service_fn(move |uri: Uri| {
+        let connector = TlsConnector::from(config.clone());
+        let host = uri.host().unwrap_or_default().to_owned();
+        let server_name = server_name.clone().unwrap_or_else(|| host.clone());
+        let port = uri.port_u16().unwrap_or(443);
+        async move {
+            let server_name = ServerName::try_from(server_name.as_str())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let stream = TcpStream::connect((host.as_str(), port)).await?;
+            connector.connect(server_name, stream).await
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_in_hex_and_base64() {
+        let hex = "00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff:00:11:22:33:44:55:66:77:88:99:aa:bb:cc:dd:ee:ff";
+        let pin = parse_pin(hex).unwrap();
+        assert_eq!(pin[1], 0x11);
+        assert_eq!(
+            parse_pin(&format!("sha256/{}", BASE64.encode(pin))).unwrap(),
+            pin
+        );
+        assert!(parse_pin("0011").is_err());
+        assert!(parse_pin("sha256/not-base64").is_err());
+    }
+
+    #[test]
+    fn client_cert_requires_key() {
+        let tls = TlsConfig {
+            client_cert: Some("cert.pem".into()),
+            ..Default::default()
+        };
+        assert!(matches!(client_config(&tls, &[]), Err(TlsConfigError(_))));
+    }
+}
@@ -0,0 +1,209 @@
+use crate::error::HubError::{self, *};
+use crate::http::connection::Backoff;
+use crate::identity::AgentIdentity;
+use reqwest::Client;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Instant};
+use tracing::{debug, error, info, warn};
+
+// Share of the token lifetime after which a new token is requested
+const REFRESH_RATIO: f64 = 0.75;
+
+#[derive(Debug, Deserialize)]
+struct ChallengeResponse {
+    challenge: String,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenRequest<'a> {
+    challenge: &'a str,
+    signature: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    token: String,
+    #[serde(alias = "expiresIn")]
+    expires_in: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    token: String,
+    expires_at: Instant,
+    refresh_at: Instant,
+}
+
+impl AccessToken {
+    pub fn secret(&self) -> &str {
+        &self.token
+    }
+
+    pub fn new(token: String, lifetime: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            token,
+            expires_at: now + lifetime,
+            refresh_at: now + lifetime.mul_f64(REFRESH_RATIO),
+        }
+    }
+}
+
+/// Short-lived Hub token shared between the refresh task and the gRPC interceptor
+#[derive(Clone, Default)]
+pub struct TokenStore {
+    token: Arc<RwLock<Option<AccessToken>>>,
+    refresh: Arc<Notify>,
+}
+
+impl TokenStore {
+    /// Returns the token if it is still valid, asking for a refresh when it is about to expire
+    pub fn current(&self) -> Option<String> {
+        let now = Instant::now();
+        let token = self.token.read().unwrap();
+        match token.as_ref() {
+            Some(token) if now < token.expires_at => {
+                if now >= token.refresh_at {
+                    self.refresh.notify_one();
+                }
+                Some(token.token.clone())
+            }
+            _ => {
+                self.refresh.notify_one();
+                None
+            }
+        }
+    }
+
+    pub fn set(&self, token: AccessToken) {
+        *self.token.write().unwrap() = Some(token);
+    }
+
+    fn time_until_refresh(&self) -> Duration {
+        match self.token.read().unwrap().as_ref() {
+            Some(token) => token.refresh_at.saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        }
+    }
+}
+
+/// Exchanges a signed challenge for an access token
+#[derive(Clone)]
+pub struct TokenClient {
+    http_client: Client,
+    challenge_url: String,
+    token_url: String,
+    agent_id: String,
+    identity: AgentIdentity,
+}
+
+impl TokenClient {
+    pub fn new(
+        http_client: Client,
+        base_url: &str,
+        challenge_path: &str,
+        token_path: &str,
+        agent_id: &str,
+        identity: AgentIdentity,
+    ) -> Self {
+        Self {
+            http_client,
+            challenge_url: format!(
+                "{}{}",
+                base_url,
+                challenge_path.replace("{agent_id}", agent_id)
+            ),
+            token_url: format!("{}{}", base_url, token_path.replace("{agent_id}", agent_id)),
+            agent_id: agent_id.to_owned(),
+            identity,
+        }
+    }
+
+    pub async fn fetch(&self) -> Result<AccessToken, HubError> {
+        let response = self.http_client.post(&self.challenge_url).send().await?;
+        if !response.status().is_success() {
+            return Err(UnexpectedStatus(response.status()));
+        }
+        let challenge = response.json::<ChallengeResponse>().await?.challenge;
+
+        let request = TokenRequest {
+            signature: self.identity.sign_challenge(&self.agent_id, &challenge),
+            challenge: &challenge,
+        };
+        let response = self
+            .http_client
+            .post(&self.token_url)
+            .json(&request)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UnexpectedStatus(response.status()));
+        }
+        let token = response.json::<TokenResponse>().await?;
+
+        debug!("Obtained a Hub token valid for {}s", token.expires_in);
+        Ok(AccessToken::new(
+            token.token,
+            Duration::from_secs(token.expires_in),
+        ))
+    }
+
+    /// Keeps `store` filled with a valid token until the returned task is aborted
+    pub fn spawn_refresh(self, store: TokenStore, mut backoff: Backoff) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = sleep(store.time_until_refresh()) => {},
+                    _ = store.refresh.notified() => {},
+                }
+                match self.fetch().await {
+                    Ok(token) => {
+                        info!("Refreshed the Hub access token");
+                        store.set(token);
+                        backoff.reset();
+                    }
+                    Err(err) => {
+                        let delay = backoff.next_delay();
+                        if err.is_retryable() {
+                            warn!(
+                                "Failed to refresh the Hub token: {}, retrying in {:?}",
+                                err, delay
+                            );
+                        } else {
+                            error!(
+                                "The Hub refused to refresh the agent token: {}, retrying in {:?}",
+                                err, delay
+                            );
+                        }
+                        sleep(delay).await;
+                    }
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_only_hands_out_valid_tokens() {
+        let store = TokenStore::default();
+        assert_eq!(store.current(), None);
+
+        store.set(AccessToken::new(
+            "token".to_owned(),
+            Duration::from_secs(60),
+        ));
+        assert_eq!(store.current(), Some("token".to_owned()));
+        assert!(store.time_until_refresh() > Duration::from_secs(40));
+
+        store.set(AccessToken::new("expired".to_owned(), Duration::ZERO));
+        assert_eq!(store.current(), None);
+    }
+}
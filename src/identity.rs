@@ -0,0 +1,91 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::sync::Arc;
+use tracing::info;
+
+use crate::error::AgentError;
+use crate::state::StateDir;
+
+const KEY_FILE: &str = "agent_key";
+
+/// Ed25519 keypair proving the agent identity to the Hub, created on first start
+#[derive(Clone)]
+pub struct AgentIdentity {
+    signing_key: Arc<SigningKey>,
+}
+
+impl AgentIdentity {
+    pub fn load_or_generate(state: &StateDir) -> Result<Self, AgentError> {
+        state.migrate_legacy(KEY_FILE, |contents| contents.len() == SECRET_KEY_LENGTH);
+
+        let path = state.file(KEY_FILE);
+        let signing_key = match fs::read(&path) {
+            Ok(bytes) => {
+                let secret: [u8; SECRET_KEY_LENGTH] = bytes.try_into().map_err(|_| {
+                    IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("{} does not hold a valid agent key", path.display()),
+                    )
+                })?;
+                SigningKey::from_bytes(&secret)
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                info!("Generating a new agent keypair in {}", path.display());
+                let signing_key = SigningKey::generate(&mut OsRng);
+                state.write_atomic(KEY_FILE, &signing_key.to_bytes())?;
+                signing_key
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            signing_key: Arc::new(signing_key),
+        })
+    }
+
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Signs `<agent_id>.<challenge>` so a challenge can't be replayed for another agent
+    pub fn sign_challenge(&self, agent_id: &str, challenge: &str) -> String {
+        let message = format!("{agent_id}.{challenge}");
+        BASE64.encode(self.signing_key.sign(message.as_bytes()).to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+    #[test]
+    fn keypair_is_persisted_and_signs_challenges() {
+        let dir = TempDir::new("agent-key");
+        let state = StateDir::open(Some(dir.path())).unwrap();
+
+        let identity = AgentIdentity::load_or_generate(&state).unwrap();
+        let reloaded = AgentIdentity::load_or_generate(&state).unwrap();
+        assert_eq!(identity.public_key(), reloaded.public_key());
+
+        let public_key: [u8; 32] = BASE64
+            .decode(identity.public_key())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature: [u8; 64] = BASE64
+            .decode(identity.sign_challenge("agent", "nonce"))
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert!(VerifyingKey::from_bytes(&public_key)
+            .unwrap()
+            .verify(b"agent.nonce", &Signature::from_bytes(&signature))
+            .is_ok());
+    }
+}
@@ -1,68 +1,176 @@
+use crate::agent_data::AgentData;
+use crate::commands::{ChannelEvent, CommandChannel};
 use crate::configuration::Configuration;
+use crate::connector::{Connection, HubConnector};
 use crate::error::AgentError;
+use crate::event_stream::{EventSender, FileEvent, EVENT_BUFFER};
+use crate::file_index::{FileFilter, FileIndex, ReadEvent};
+use crate::file_info::FileInfo;
+use crate::file_lister::SharedScanProgress;
+use crate::health::SharedAgentHealth;
+use crate::heartbeat::Heartbeat;
+use crate::hot_reload::ConfigHandle;
+use crate::http::compatibility::{COMMAND_CHANNEL, FILE_EVENTS, HEARTBEAT};
+use crate::http::grpc::tidybee_events::HubInstruction;
 use crate::http::hub::Hub;
+use crate::http::registration::AgentRegistration;
+use crate::identity::AgentIdentity;
+use crate::logging::Logging;
 use crate::server::ServerBuilder;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::{borrow, env, thread};
-use tokio::{sync::mpsc, time};
-use tracing::{error, Level};
+use crate::state::StateDir;
+use notify_debouncer_full::DebouncedEvent;
+use std::path::PathBuf;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{error, info};
+
+pub use crate::cli::{execute, Cli};
 
 mod agent_data;
 mod agent_uuid;
+mod cli;
+mod commands;
 mod configuration;
+mod connector;
 mod error;
+mod event_stream;
+mod file_index;
 mod file_info;
 mod file_lister;
+mod file_type;
 mod file_watcher;
+mod health;
+mod heartbeat;
+mod hot_reload;
 mod http;
+mod identity;
+mod logging;
+mod outbox;
 mod server;
+mod similarity;
+mod state;
+mod telemetry;
+#[cfg(test)]
+mod test_support;
+mod validation;
 
-lazy_static! {
-    static ref CLI_LOGGING_LEVEL: HashMap<String, Level> = {
-        let mut m = HashMap::new();
-        m.insert("trace".to_owned(), Level::TRACE);
-        m.insert("debug".to_owned(), Level::DEBUG);
-        m.insert("info".to_owned(), Level::INFO);
-        m.insert("warn".to_owned(), Level::WARN);
-        m.insert("error".to_owned(), Level::ERROR);
-        m
-    };
+fn hub_client(config: &Configuration) -> Result<Hub, AgentError> {
+    let state = StateDir::open(config.state_config.dir.as_deref())?;
+    agent_uuid::migrate(&state);
+    let identity = AgentIdentity::load_or_generate(&state)?;
+    Hub::new(
+        config.hub_config.clone(),
+        AgentRegistration::build(config, identity.public_key()),
+        identity,
+        state,
+        config.agent_data.minimal_version.clone(),
+    )
+    .map_err(|err| AgentError::HubClientCreationFailed(err.to_string()))
 }
 
-pub async fn run() -> Result<(), AgentError> {
-    let config = match Configuration::init() {
-        Ok(config) => config,
-        Err(err) => {
-            return Err(err);
-        }
-    };
+// Lists the watched directories into the index, in place of what was indexed below them
+fn index_directories(
+    config: &Configuration,
+    file_index: &FileIndex,
+    scan_progress: &SharedScanProgress,
+) -> Result<Vec<FileInfo>, AgentError> {
+    let files = file_lister::scan_directories(
+        config.filesystem_interface_config.dir.clone(),
+        &config.similarity_config,
+        scan_progress,
+    )?;
+    file_index.replace(&watched_roots(config), files.clone());
+    Ok(files)
+}
+
+// Hashing can take a while, the event loop keeps running meanwhile
+fn spawn_scan(
+    config: &Configuration,
+    file_index: &FileIndex,
+    scan_progress: &SharedScanProgress,
+) -> JoinHandle<Result<Vec<FileInfo>, AgentError>> {
+    let (config, file_index, scan_progress) =
+        (config.clone(), file_index.clone(), scan_progress.clone());
+    tokio::task::spawn_blocking(move || index_directories(&config, &file_index, &scan_progress))
+}
 
-    let selected_cli_logger_level = CLI_LOGGING_LEVEL
-        .get(&config.logger_config.term_level)
-        .map_or(Level::INFO, borrow::ToOwned::to_owned);
+async fn send_files(hub_client: &mut Hub, files: Vec<FileInfo>) {
+    if !hub_client.allows(FILE_EVENTS) {
+        return;
+    }
+    if let Err(err) = hub_client.grpc_client.send_create_events_once(files).await {
+        error!("{err}");
+    }
+}
 
-    match env::var("TIDY_BACKTRACE") {
-        Ok(env) => {
-            if env == "1" {
-                tracing_subscriber::fmt()
-                    .with_target(true)
-                    .with_max_level(selected_cli_logger_level)
-                    .pretty()
-                    .init();
+// Updates the index and the local event stream, which work without the Hub
+fn index_event(
+    file_index: &FileIndex,
+    events: &EventSender,
+    roots: &[PathBuf],
+    file_event: &ReadEvent,
+) {
+    telemetry::event_dequeued();
+    file_index.apply(file_event);
+    if let Some(event) = FileEvent::from_debounced(&file_event.event, roots) {
+        // Nobody may be listening
+        let _ = events.send(event);
+    }
+}
+
+// Reads the watcher events from the disk ahead of the run loop, in order and off the async workers
+fn read_events(
+    mut events: UnboundedReceiver<DebouncedEvent>,
+    config: watch::Receiver<Configuration>,
+) -> UnboundedReceiver<ReadEvent> {
+    let (sender, read) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            let similarity = config.borrow().similarity_config.clone();
+            let read = move || ReadEvent::read(event, &similarity);
+            let Ok(event) = tokio::task::spawn_blocking(read).await else {
+                break;
+            };
+            if sender.send(event).is_err() {
+                break;
             }
         }
-        Err(_) => {
-            tracing_subscriber::fmt()
-                .with_target(false)
-                .with_max_level(selected_cli_logger_level)
-                .compact()
-                .init();
-        }
-    };
+    });
+    read
+}
+
+// Canonical like the paths of the watcher events
+fn watched_roots(config: &Configuration) -> Vec<PathBuf> {
+    config
+        .filesystem_interface_config
+        .dir
+        .iter()
+        .filter_map(|dir| dir.canonicalize().ok())
+        .collect()
+}
+
+async fn run(
+    mut config_updates: watch::Receiver<Configuration>,
+    config_handle: ConfigHandle,
+    logging: Logging,
+) -> Result<(), AgentError> {
+    let mut config = config_updates.borrow_and_update().clone();
+    let mut hub_client = hub_client(&config)?;
+    hub_client.load_outbox();
+    let file_index = FileIndex::default();
+    let events = EventSender::new(EVENT_BUFFER);
+    let health = SharedAgentHealth::default();
+    let scan_progress = SharedScanProgress::default();
 
     let server = ServerBuilder::new()
-        .inject_global_configuration(config.clone())
+        .inject_global_configuration(config_updates.clone())
+        .inject_hub_status(hub_client.status())
+        .inject_file_index(file_index.clone())
+        .inject_event_sender(events.clone())
+        .inject_metrics(telemetry::install())
+        .inject_agent_health(health.clone())
+        .inject_scan_progress(scan_progress.clone())
         .build(
             config.agent_data.latest_version.clone(),
             config.agent_data.minimal_version.clone(),
@@ -71,58 +179,187 @@ pub async fn run() -> Result<(), AgentError> {
             &config.server_config.log_level,
         );
 
-    let mut hub_client = Hub::new(config.hub_config.clone()).unwrap();
-
     tokio::spawn(async move {
-        server.start().await;
-    });
-
-    let mut timeout = 5;
-    loop {
-        time::sleep(time::Duration::from_secs(timeout)).await;
-        if let Err(err) = hub_client.connect().await {
-            error!(
-                "Error connecting to the hub: {}, retrying in {}",
-                err,
-                timeout * 2
-            );
-        } else {
-            break;
+        if let Err(err) = server.start().await {
+            error!("{}", err);
         }
-        timeout *= 2;
-    }
+    });
+    #[cfg(unix)]
+    health::notify_systemd(health.clone(), hub_client.status());
 
-    match file_lister::list_directories(config.clone().filesystem_interface_config.dir) {
-        Ok(files_vec) => {
-            if let Err(err) = hub_client
-                .grpc_client
-                .send_create_events_once(files_vec)
-                .await
-            {
-                error!("{err}");
-            }
-        }
+    // The index is built and kept up to date before the Hub is reached, so the local API works offline
+    match index_directories(&config, &file_index, &scan_progress) {
+        Ok(_) => health.lock().unwrap().initial_scan.ready(),
         Err(error) => {
             error!("{}", error);
+            health.lock().unwrap().initial_scan.failure(&error);
         }
     }
 
     let (file_watcher_sender, file_watcher_receiver) = mpsc::unbounded_channel();
-    let file_watcher_thread: thread::JoinHandle<()> = thread::spawn(move || {
-        file_watcher::watch_directories(
-            config.filesystem_interface_config.dir.clone(),
-            file_watcher_sender,
-        );
-    });
+    let file_watcher = file_watcher::spawn(
+        config.filesystem_interface_config.dir.clone(),
+        file_watcher_sender,
+        health.clone(),
+    );
+    let mut file_watcher_receiver = read_events(file_watcher_receiver, config_updates.clone());
 
-    if let Err(err) = hub_client
-        .grpc_client
-        .send_events(file_watcher_receiver)
-        .await
-    {
-        error!("{err}");
+    let mut roots = watched_roots(&config);
+    // The agent runs without the Hub until a client connects, it then takes the place of this one
+    let mut connector = HubConnector::default();
+    let mut connecting = crate::hub_client(&config)?;
+    connecting.share_status(hub_client.status());
+    connector.connect(connecting);
+    let mut agent_data = AgentData::build(
+        config.agent_data.latest_version.clone(),
+        config.agent_data.minimal_version.clone(),
+        config.filesystem_interface_config.dir.clone(),
+    );
+    let mut heartbeat = Heartbeat::new(&config);
+    let mut command_channel = CommandChannel::new(&config.hub_config);
+    let mut rescan = None;
+    // Commands from the Hub, run in the background like rescans
+    let mut running_commands = JoinSet::new();
+    // The configuration of the Hub in effect while connecting to another one
+    let mut switching_from: Option<Configuration> = None;
+    loop {
+        tokio::select! {
+            file_event = file_watcher_receiver.recv() => match file_event {
+                Some(file_event) => {
+                    index_event(&file_index, &events, &roots, &file_event);
+                    // Kept in the outbox while the Hub is out of reach, so deletions and moves reach it too
+                    if hub_client.is_connected() && !hub_client.allows(FILE_EVENTS) {
+                        continue;
+                    }
+                    if let Err(err) = hub_client.grpc_client.send_event(&file_event).await {
+                        error!("{err}");
+                    }
+                }
+                None => break,
+            },
+            Ok(()) = config_updates.changed() => {
+                let new_config = config_updates.borrow_and_update().clone();
+                let previous = config.clone();
+                config = hot_reload::apply(
+                    &config,
+                    &new_config,
+                    &mut hub_client,
+                    &file_watcher,
+                    &file_index,
+                    &logging,
+                )
+                .await;
+                if hot_reload::hub_changed(&previous, &config) {
+                    // Until the new Hub is reached, the current client keeps working
+                    let in_effect = switching_from.take().unwrap_or(previous);
+                    if !hot_reload::hub_changed(&in_effect, &config) {
+                        connector.cancel();
+                    } else {
+                        match crate::hub_client(&config) {
+                            Ok(mut hub) => {
+                                info!("Connecting to the Hub with the new configuration");
+                                hub.share_status(hub_client.status());
+                                if hub_client.is_connected() {
+                                    connector.switch(hub);
+                                    switching_from = Some(in_effect);
+                                } else {
+                                    connector.connect(hub);
+                                }
+                            }
+                            Err(err) => {
+                                error!("Keeping the previous Hub: {}", err);
+                                config.hub_config = in_effect.hub_config;
+                                config.agent_data = in_effect.agent_data;
+                            }
+                        }
+                    }
+                }
+                roots = watched_roots(&config);
+                heartbeat.reconfigure(&config);
+                config_handle.settle(&new_config, &config);
+            }
+            channel_event = command_channel.next(),
+                if hub_client.allows(COMMAND_CHANNEL) => match channel_event {
+                ChannelEvent::Command(command) => {
+                    command_channel.send(commands::accepted(&command)).await;
+                    running_commands.spawn(commands::execute(
+                        command,
+                        config.clone(),
+                        file_index.clone(),
+                        config_handle.clone(),
+                    ));
+                }
+                ChannelEvent::Reopen => command_channel.open(&mut hub_client.grpc_client).await,
+            },
+            _ = heartbeat.tick(), if hub_client.allows(HEARTBEAT) => {
+                for instruction in heartbeat::beat(&mut hub_client, &mut agent_data, &config).await {
+                    match instruction {
+                        HubInstruction::Rescan => {
+                            if rescan.is_some() {
+                                info!("Already rescanning the watched directories");
+                                continue;
+                            }
+                            info!("Rescanning the watched directories as asked by the Hub");
+                            rescan = Some(spawn_scan(&config, &file_index, &scan_progress));
+                        }
+                        HubInstruction::ReloadConfiguration => {
+                            config_handle.reload("the Hub asked for it");
+                        }
+                        HubInstruction::NoInstruction => {}
+                    }
+                }
+            }
+            connection = connector.next() => match connection {
+                Connection::Connected(mut hub) => {
+                    switching_from = None;
+                    hub.take_over(&mut hub_client);
+                    hub_client = *hub;
+                    command_channel.reset(&config.hub_config);
+                    if hub_client.allows(FILE_EVENTS) {
+                        if let Err(err) = hub_client.grpc_client.resend_pending().await {
+                            error!("{err}");
+                        }
+                    }
+                    // The outbox above carries the changes seen while the Hub was out of reach, this refreshes every file
+                    send_files(&mut hub_client, file_index.filter(&FileFilter::default())).await;
+                }
+                Connection::SwitchFailed => {
+                    error!("Keeping the connection to the previous Hub");
+                    let requested = config.clone();
+                    if let Some(in_effect) = switching_from.take() {
+                        config.hub_config = in_effect.hub_config;
+                        config.agent_data = in_effect.agent_data;
+                    }
+                    heartbeat.reconfigure(&config);
+                    config_handle.settle(&requested, &config);
+                }
+            },
+            Some(executed) = running_commands.join_next(), if !running_commands.is_empty() => {
+                match executed {
+                    Ok(executed) => {
+                        let result = commands::finish(executed, &mut hub_client, &file_index).await;
+                        command_channel.send(result).await;
+                    }
+                    Err(err) => error!("A command from the Hub stopped: {}", err),
+                }
+            }
+            scanned = async { rescan.as_mut().unwrap().await }, if rescan.is_some() => {
+                rescan = None;
+                match scanned {
+                    Ok(Ok(files)) => {
+                        // The watched directories may have changed during the scan
+                        file_index.retain_roots(&roots);
+                        let files = files
+                            .into_iter()
+                            .filter(|file| roots.iter().any(|root| file.path.starts_with(root)))
+                            .collect();
+                        send_files(&mut hub_client, files).await;
+                    }
+                    Ok(Err(err)) => error!("{}", err),
+                    Err(err) => error!("The rescan failed: {}", err),
+                }
+            }
+        }
     }
-
-    file_watcher_thread.join().unwrap();
     Ok(())
 }
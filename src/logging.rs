@@ -0,0 +1,163 @@
+use crate::configuration::Configuration;
+use crate::state;
+use crate::validation::LOG_LEVELS;
+use std::env;
+use std::path::PathBuf;
+use tracing::info;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::{self, writer::BoxMakeWriter, MakeWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+const LOG_FILE_PREFIX: &str = "tidybee-agent";
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Filters of the installed subscriber, changed when the configuration is reloaded
+pub struct Logging {
+    term: FilterHandle,
+    file: Option<FilterHandle>,
+}
+
+impl Logging {
+    pub fn set_term_level(&self, directives: &str) {
+        set_filter(&self.term, directives, "terminal");
+    }
+
+    pub fn set_file_level(&self, directives: &str) {
+        if let Some(file) = &self.file {
+            set_filter(file, directives, "file");
+        }
+    }
+}
+
+fn set_filter(handle: &FilterHandle, directives: &str, sink: &str) {
+    match handle.reload(env_filter(directives)) {
+        Ok(()) => info!("Logging to the {} at {}", sink, directives),
+        Err(err) => tracing::error!("Could not change the {} log level: {}", sink, err),
+    }
+}
+
+/// A level such as `info`, or per-module directives such as `warn,tidybee_agent::http=debug`
+pub fn env_filter(directives: &str) -> EnvFilter {
+    parse_filter(directives).unwrap_or_else(|_| EnvFilter::new("info"))
+}
+
+// A bare word that is not a level would be taken for a target logged at every level
+pub fn parse_filter(directives: &str) -> Result<EnvFilter, String> {
+    let unknown = directives
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty() && !directive.contains('='))
+        .find(|level| *level != "off" && !LOG_LEVELS.contains(level));
+    if let Some(unknown) = unknown {
+        return Err(format!(
+            "unknown log level {unknown:?}, expected off or one of {}",
+            LOG_LEVELS.join(", ")
+        ));
+    }
+    EnvFilter::builder()
+        .parse(directives)
+        .map_err(|err| err.to_string())
+}
+
+/// Logs to `writer` only, for one-shot commands
+pub fn init_terminal(level: &str, writer: BoxMakeWriter) -> Logging {
+    install(level, writer, false, None)
+}
+
+/// Logs to `writer` at `term_level` and to rotating files at `file_level`
+pub fn init(config: &Configuration, writer: BoxMakeWriter) -> Logging {
+    let logger_config = &config.logger_config;
+    let file = match logger_config.file_level.as_str() {
+        "off" => None,
+        level => file_appender(config).map(|appender| (level, appender)),
+    };
+    install(&logger_config.term_level, writer, logger_config.json, file)
+}
+
+fn install(
+    term_level: &str,
+    writer: BoxMakeWriter,
+    json: bool,
+    file: Option<(&str, RollingFileAppender)>,
+) -> Logging {
+    let (term_filter, term) = reload::Layer::new(env_filter(term_level));
+    let mut layers: Vec<BoxedLayer> = vec![fmt_layer(writer, json, true)
+        .with_filter(term_filter)
+        .boxed()];
+    let file = file.map(|(file_level, appender)| {
+        let (file_filter, file) = reload::Layer::new(env_filter(file_level));
+        layers.push(
+            fmt_layer(appender, json, false)
+                .with_filter(file_filter)
+                .boxed(),
+        );
+        file
+    });
+
+    tracing_subscriber::registry().with(layers).init();
+    Logging { term, file }
+}
+
+fn fmt_layer<W>(writer: W, json: bool, ansi: bool) -> BoxedLayer
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    if json {
+        return layer.json().boxed();
+    }
+    // TIDY_BACKTRACE=1 trades the compact output for a verbose one with targets and source locations
+    match env::var("TIDY_BACKTRACE").as_deref() {
+        Ok("1") => layer.with_target(true).pretty().boxed(),
+        _ => layer.with_target(false).compact().boxed(),
+    }
+}
+
+// Defaults to a `logs` directory in the state directory, which is created first so that it is private
+fn log_dir(config: &Configuration) -> PathBuf {
+    match &config.logger_config.dir {
+        Some(dir) => dir.clone(),
+        None => {
+            let state_dir = config
+                .state_config
+                .dir
+                .clone()
+                .unwrap_or_else(state::default_dir);
+            if !state_dir.is_dir() {
+                // Building the appender reports the failure
+                let _ = state::create_private(&state_dir);
+            }
+            state_dir.join("logs")
+        }
+    }
+}
+
+fn file_appender(config: &Configuration) -> Option<RollingFileAppender> {
+    let dir = log_dir(config);
+    let rotation = match config.logger_config.rotation.as_str() {
+        "minutely" => Rotation::MINUTELY,
+        "hourly" => Rotation::HOURLY,
+        "never" => Rotation::NEVER,
+        _ => Rotation::DAILY,
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log");
+    // 0 keeps every file
+    if config.logger_config.max_files > 0 {
+        builder = builder.max_log_files(config.logger_config.max_files);
+    }
+
+    match builder.build(&dir) {
+        Ok(appender) => Some(appender),
+        // No subscriber is installed yet to report it
+        Err(err) => {
+            eprintln!("Not logging to {}: {}", dir.display(), err);
+            None
+        }
+    }
+}
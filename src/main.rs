@@ -1,8 +1,12 @@
-use tracing::error;
+use clap::Parser;
+use std::process::ExitCode;
+use tidybee_agent::Cli;
 
 #[tokio::main]
-async fn main() {
-    if let Err(err) = tidybee_agent::run().await {
-        error!("Error: {}", err);
+async fn main() -> ExitCode {
+    if let Err(err) = tidybee_agent::execute(Cli::parse()).await {
+        eprintln!("Error: {}", err);
+        return ExitCode::FAILURE;
     }
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,260 @@
+use crate::http::grpc::tidybee_events::{FileEventRequest, FolderEventRequest};
+use crate::state::StateDir;
+use prost::Message;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use tracing::{info, warn};
+
+const OUTBOX_FILE: &str = "outbox";
+// Where earlier versions kept the last number, read to carry on from it
+const SEQUENCE_FILE: &str = "sequence";
+// The journal is rewritten once it holds that many records beyond the pending events
+const COMPACTION_THRESHOLD: usize = 10_000;
+// Beyond this, new events are refused rather than numbered, the index sent on the next connection covers the files that remain
+const MAX_PENDING: usize = 100_000;
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum OutboxEvent {
+    #[prost(message, tag = "1")]
+    File(FileEventRequest),
+    #[prost(message, tag = "2")]
+    Folder(FolderEventRequest),
+}
+
+impl OutboxEvent {
+    fn sequence(&self) -> u64 {
+        match self {
+            Self::File(event) => event.sequence,
+            Self::Folder(event) => event.sequence,
+        }
+    }
+}
+
+// One line of the journal, replayed in order on load
+#[derive(Clone, PartialEq, Message)]
+struct Record {
+    #[prost(oneof = "OutboxEvent", tags = "1, 2")]
+    event: Option<OutboxEvent>,
+    /// The Hub holds every event up to this one
+    #[prost(uint64, tag = "3")]
+    acknowledged: u64,
+    /// This event was delivered or refused for good
+    #[prost(uint64, tag = "4")]
+    done: u64,
+    /// The last number handed out, kept for when nothing is pending
+    #[prost(uint64, tag = "5")]
+    last: u64,
+}
+
+/// Numbers the events sent to the Hub, from 1 and without gaps, and keeps them until the Hub has them, across restarts
+///
+/// Records are appended to a journal in the state directory without syncing it, so that sending stays cheap:
+/// a crash of the agent loses nothing, a power cut may lose what came since the journal was last rewritten.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    state: Option<StateDir>,
+    journal: Option<File>,
+    records: usize,
+    last: u64,
+    acknowledged: u64,
+    pending: BTreeMap<u64, OutboxEvent>,
+}
+
+impl Outbox {
+    pub fn load(state: &StateDir) -> Self {
+        let mut outbox = Self {
+            state: Some(state.clone()),
+            ..Default::default()
+        };
+        if let Ok(contents) = fs::read_to_string(state.file(SEQUENCE_FILE)) {
+            outbox.last = contents.trim().parse().unwrap_or_default();
+        }
+        if let Ok(contents) = fs::read(state.file(OUTBOX_FILE)) {
+            outbox.replay(&contents);
+        }
+        if !outbox.pending.is_empty() {
+            info!(
+                "{} event(s) of a previous run wait for the Hub",
+                outbox.pending.len()
+            );
+        }
+        outbox.compact();
+        outbox
+    }
+
+    fn replay(&mut self, mut contents: &[u8]) {
+        while !contents.is_empty() {
+            let record = match Record::decode_length_delimited(&mut contents) {
+                Ok(record) => record,
+                // Cut short by a crash while appending
+                Err(err) => {
+                    warn!("Ignoring the end of the event outbox: {}", err);
+                    break;
+                }
+            };
+            if let Some(event) = record.event {
+                self.last = self.last.max(event.sequence());
+                self.pending.insert(event.sequence(), event);
+            }
+            self.last = self.last.max(record.last);
+            self.acknowledged = self.acknowledged.max(record.acknowledged);
+            self.pending.remove(&record.done);
+        }
+        self.pending = self.pending.split_off(&(self.acknowledged + 1));
+    }
+
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// Reserves `count` numbers, returning the first one, or `None` when the events would not fit
+    pub fn assign(&mut self, count: usize) -> Option<u64> {
+        if self.pending.len() + count > MAX_PENDING {
+            return None;
+        }
+        let first = self.last + 1;
+        self.last += count as u64;
+        Some(first)
+    }
+
+    /// Keeps events numbered by `assign` until they are settled
+    pub fn keep(&mut self, events: impl IntoIterator<Item = OutboxEvent>) {
+        let records: Vec<Record> = events
+            .into_iter()
+            .map(|event| {
+                self.pending.insert(event.sequence(), event.clone());
+                Record {
+                    event: Some(event),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        debug_assert!(self.pending.len() <= MAX_PENDING);
+        self.append(records);
+    }
+
+    /// Drops the events the Hub acknowledged up to `acknowledged`, and the `done` ones
+    pub fn settle(&mut self, acknowledged: u64, done: &[u64]) {
+        let mut records = Vec::new();
+        if acknowledged > self.acknowledged {
+            self.acknowledged = acknowledged;
+            self.pending = self.pending.split_off(&(acknowledged + 1));
+            records.push(Record {
+                acknowledged,
+                ..Default::default()
+            });
+        }
+        for &sequence in done {
+            if self.pending.remove(&sequence).is_some() {
+                records.push(Record {
+                    done: sequence,
+                    ..Default::default()
+                });
+            }
+        }
+        self.append(records);
+    }
+
+    /// The events still to deliver, oldest first
+    pub fn pending(&self) -> Vec<OutboxEvent> {
+        self.pending.values().cloned().collect()
+    }
+
+    fn append(&mut self, records: Vec<Record>) {
+        if records.is_empty() || self.state.is_none() {
+            return;
+        }
+        if self.records > self.pending.len() + COMPACTION_THRESHOLD {
+            self.compact();
+            return;
+        }
+        let mut contents = Vec::new();
+        for record in &records {
+            record
+                .encode_length_delimited(&mut contents)
+                .expect("a Vec grows as needed");
+        }
+        let Some(journal) = &mut self.journal else {
+            return;
+        };
+        match journal.write_all(&contents) {
+            Ok(()) => self.records += records.len(),
+            Err(err) => warn!("Could not save events to the outbox: {}", err),
+        }
+    }
+
+    // Rewrites the journal with only what is still needed
+    fn compact(&mut self) {
+        let Some(state) = &self.state else {
+            return;
+        };
+        let head = Record {
+            last: self.last,
+            acknowledged: self.acknowledged,
+            ..Default::default()
+        };
+        let records = std::iter::once(head).chain(self.pending.values().map(|event| Record {
+            event: Some(event.clone()),
+            ..Default::default()
+        }));
+        let mut contents = Vec::new();
+        for record in records {
+            record
+                .encode_length_delimited(&mut contents)
+                .expect("a Vec grows as needed");
+        }
+        if let Err(err) = state.write_atomic(OUTBOX_FILE, &contents) {
+            warn!("Could not save the event outbox: {}", err);
+            return;
+        }
+        let _ = fs::remove_file(state.file(SEQUENCE_FILE));
+        self.records = self.pending.len() + 1;
+        self.journal = match state.open_append(OUTBOX_FILE) {
+            Ok(journal) => Some(journal),
+            Err(err) => {
+                warn!("Could not open the event outbox: {}", err);
+                None
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn event(sequence: u64) -> OutboxEvent {
+        OutboxEvent::File(FileEventRequest {
+            sequence,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn outbox_survives_restarts() {
+        let dir = TempDir::new("outbox");
+        let state = StateDir::open(Some(dir.path())).unwrap();
+
+        let mut outbox = Outbox::load(&state);
+        assert_eq!(outbox.assign(3), Some(1));
+        outbox.keep((1..=3).map(event));
+        assert_eq!(outbox.assign(2), Some(4));
+        outbox.keep((4..=5).map(event));
+        outbox.settle(2, &[4]);
+
+        let mut outbox = Outbox::load(&state);
+        assert_eq!(outbox.last(), 5);
+        let pending: Vec<u64> = outbox.pending().iter().map(OutboxEvent::sequence).collect();
+        assert_eq!(pending, vec![3, 5]);
+        assert_eq!(outbox.assign(1), Some(6));
+        outbox.keep([event(6)]);
+        assert_eq!(outbox.assign(MAX_PENDING), None);
+
+        outbox.settle(6, &[]);
+        let outbox = Outbox::load(&state);
+        assert!(outbox.pending().is_empty());
+        assert_eq!(outbox.last(), 6);
+    }
+}
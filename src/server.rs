@@ -1,13 +1,27 @@
 use crate::agent_data::AgentData;
 use crate::configuration;
-use crate::http::routes::{get_config, get_status, AgentDataState, GlobalConfigState};
-use axum::{routing::get, Router};
+use crate::error::AgentError;
+use crate::event_stream::{EventSender, EVENT_BUFFER};
+use crate::file_index::FileIndex;
+use crate::file_lister::SharedScanProgress;
+use crate::health::SharedAgentHealth;
+use crate::http::auth::{authorize, Access, AuthState};
+use crate::http::connection::SharedHubConnectionStatus;
+use crate::http::routes::{
+    get_config, get_duplicates, get_events, get_file, get_files, get_health, get_hub_status,
+    get_metrics, get_ready, get_similar, get_similar_images, get_status, AgentDataState,
+    EventsState, FilesState, GlobalConfigState, HealthState, HubStatusState, MetricsState,
+};
+use axum::{middleware, routing::get, Router};
 use lazy_static::lazy_static;
+use metrics_exporter_prometheus::PrometheusHandle;
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use sysinfo::System;
 use tokio::net::TcpListener;
+use tokio::sync::watch;
 use tower_http::trace::{self, TraceLayer};
 use tracing::{error, Level};
 
@@ -29,15 +43,31 @@ pub struct Server {
     router: Router,
 }
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct ServerBuilder {
     router: Router,
-    global_configuration: configuration::Configuration,
+    global_configuration: watch::Receiver<configuration::Configuration>,
+    hub_status: SharedHubConnectionStatus,
+    file_index: FileIndex,
+    events: EventSender,
+    metrics: Option<PrometheusHandle>,
+    agent_health: SharedAgentHealth,
+    scan_progress: SharedScanProgress,
 }
 
-trait ServerConfig {
-    fn host(&self) -> &str;
-    fn route(&self) -> &str;
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self {
+            router: Router::default(),
+            global_configuration: watch::channel(configuration::Configuration::default()).1,
+            hub_status: SharedHubConnectionStatus::default(),
+            file_index: FileIndex::default(),
+            events: EventSender::new(EVENT_BUFFER),
+            metrics: None,
+            agent_health: SharedAgentHealth::default(),
+            scan_progress: SharedScanProgress::default(),
+        }
+    }
 }
 
 impl ServerBuilder {
@@ -45,14 +75,46 @@ impl ServerBuilder {
         Self::default()
     }
 
+    /// Serves the latest configuration published on `global_configuration`
     pub fn inject_global_configuration(
         mut self,
-        global_configuration: configuration::Configuration,
+        global_configuration: watch::Receiver<configuration::Configuration>,
     ) -> Self {
         self.global_configuration = global_configuration;
         self
     }
 
+    pub fn inject_hub_status(mut self, hub_status: SharedHubConnectionStatus) -> Self {
+        self.hub_status = hub_status;
+        self
+    }
+
+    pub fn inject_file_index(mut self, file_index: FileIndex) -> Self {
+        self.file_index = file_index;
+        self
+    }
+
+    /// Streams the events published on `events` to the `/events` subscribers
+    pub fn inject_event_sender(mut self, events: EventSender) -> Self {
+        self.events = events;
+        self
+    }
+
+    pub fn inject_metrics(mut self, metrics: Option<PrometheusHandle>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn inject_agent_health(mut self, agent_health: SharedAgentHealth) -> Self {
+        self.agent_health = agent_health;
+        self
+    }
+
+    pub fn inject_scan_progress(mut self, scan_progress: SharedScanProgress) -> Self {
+        self.scan_progress = scan_progress;
+        self
+    }
+
     pub fn build(
         self,
         latest_version: String,
@@ -67,10 +129,33 @@ impl ServerBuilder {
                 minimal_version,
                 dirs_watch,
             ))),
+            config: self.global_configuration.clone(),
+            index: self.file_index.clone(),
+            scan_progress: self.scan_progress,
+            hub_status: self.hub_status.clone(),
+            system: Arc::new(Mutex::new(System::new())),
+        };
+        let files_state = FilesState {
+            index: self.file_index,
+            config: self.global_configuration.clone(),
+        };
+        let events_state = EventsState {
+            events: self.events,
+            config: self.global_configuration.clone(),
+        };
+        let metrics_state = MetricsState {
+            handle: self.metrics,
         };
         let global_config_state = GlobalConfigState {
             config: self.global_configuration,
         };
+        let health_state = HealthState {
+            health: self.agent_health,
+            hub_status: self.hub_status.clone(),
+        };
+        let hub_status_state = HubStatusState {
+            status: self.hub_status,
+        };
 
         let server_logging_level: Level = AGENT_LOGGING_LEVEL.get(logging_level).map_or_else(
             || {
@@ -83,10 +168,50 @@ impl ServerBuilder {
             |level| *level,
         );
 
-        let router = self
-            .router
+        let read_auth = AuthState {
+            config: global_config_state.config.clone(),
+            required: Access::Read,
+        };
+        let admin_auth = AuthState {
+            required: Access::Admin,
+            ..read_auth.clone()
+        };
+        // Probes from orchestrators and supervisors don't carry keys
+        let public_routes = Router::new()
+            .route("/health", get(get_health))
+            .route("/ready", get(get_ready).with_state(health_state));
+        let read_routes = Router::new()
             .route("/get_status", get(get_status).with_state(agent_data_state))
+            .route(
+                "/hub_status",
+                get(get_hub_status).with_state(hub_status_state),
+            )
+            .route("/files", get(get_files).with_state(files_state.clone()))
+            .route("/files/info", get(get_file).with_state(files_state.clone()))
+            .route(
+                "/duplicates",
+                get(get_duplicates).with_state(files_state.clone()),
+            )
+            .route(
+                "/duplicates/similar",
+                get(get_similar).with_state(files_state.clone()),
+            )
+            .route(
+                "/duplicates/images",
+                get(get_similar_images).with_state(files_state),
+            )
+            .route("/events", get(get_events).with_state(events_state))
+            .route("/metrics", get(get_metrics).with_state(metrics_state))
+            .route_layer(middleware::from_fn_with_state(read_auth, authorize));
+        let admin_routes = Router::new()
             .route("/config", get(get_config).with_state(global_config_state))
+            .route_layer(middleware::from_fn_with_state(admin_auth, authorize));
+
+        let router = self
+            .router
+            .merge(public_routes)
+            .merge(read_routes)
+            .merge(admin_routes)
             .layer(
                 TraceLayer::new_for_http()
                     .make_span_with(trace::DefaultMakeSpan::new().level(server_logging_level))
@@ -99,25 +224,21 @@ impl ServerBuilder {
 }
 
 impl Server {
-    pub async fn start(self) {
-        let addr: SocketAddr = match self.address.parse() {
-            Ok(addr) => addr,
-            Err(_) => {
-                let default_config: Self = Self::default();
-                error!(
-                    "Invalid host or port: {}, defaulting to {}",
-                    self.address, default_config.address
-                );
-                default_config.address.parse().unwrap()
-            }
-        };
-        let tcp_listener = match TcpListener::bind::<SocketAddr>(addr).await {
-            Ok(tcp_listener) => tcp_listener,
-            Err(e) => {
-                error!("Failed to bind to {}: {}", addr.to_string(), e);
-                return;
-            }
+    pub async fn start(self) -> Result<(), AgentError> {
+        let failed = |err: &dyn std::fmt::Display| {
+            AgentError::ServerFailed(self.address.clone(), err.to_string())
         };
-        axum::serve(tcp_listener, self.router).await.unwrap();
+        let addr: SocketAddr = self.address.parse().map_err(|err| failed(&err))?;
+        let tcp_listener = TcpListener::bind::<SocketAddr>(addr)
+            .await
+            .map_err(|err| failed(&err))?;
+        // Peer addresses let the API tell local clients apart
+        axum::serve(
+            tcp_listener,
+            self.router
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .map_err(|err| failed(&err))
     }
 }
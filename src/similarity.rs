@@ -0,0 +1,373 @@
+use crate::configuration::SimilarityConfig;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Lower thresholds split the fingerprints into bands so short that clustering compares most pairs
+pub const MIN_THRESHOLD: f64 = 0.75;
+/// Larger distances between image hashes do the same
+pub const MAX_IMAGE_DISTANCE: u32 = 16;
+// Files sharing a band are only compared with this many of them, the closest by fingerprint, so that many similar files
+// take linear time, at the cost of splitting clusters only linked through farther files
+const BUCKET_NEIGHBOURS: usize = 32;
+
+// Words per shingle, enough for a changed line or date to only touch a few shingles
+const SHINGLE_WORDS: usize = 3;
+// Binary files hold a NUL byte early on
+const SNIFF_LEN: usize = 8192;
+
+// Office documents are zip archives, their text is in these XML entries
+const DOCUMENT_ENTRIES: [(&str, &str); 6] = [
+    ("docx", "word/document.xml"),
+    ("pptx", "ppt/slides/"),
+    ("xlsx", "xl/sharedStrings.xml"),
+    ("odt", "content.xml"),
+    ("odp", "content.xml"),
+    ("ods", "content.xml"),
+];
+
+// Side of the grayscale thumbnail the image hash is computed from, one column more to compare neighbours
+const IMAGE_HASH_SIDE: u32 = 8;
+
+// Files above the size limit are not fingerprinted
+fn within_limit(size: u64, config: &SimilarityConfig) -> bool {
+    size != 0 && size <= config.max_file_size
+}
+
+/// SimHash of the word shingles of a text or office document, similar contents giving close fingerprints
+pub fn fingerprint(path: &Path, size: u64, config: &SimilarityConfig) -> Option<u64> {
+    if !config.enabled || !within_limit(size, config) || is_image(path) {
+        return None;
+    }
+    let text = match read_text(path, config.max_file_size) {
+        Ok(text) => text?,
+        Err(err) => {
+            debug!("Could not read the text of {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    simhash(&text)
+}
+
+fn read_text(path: &Path, limit: u64) -> io::Result<Option<String>> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if let Some(extension) = extension {
+        let entries: Vec<&str> = DOCUMENT_ENTRIES
+            .iter()
+            .filter(|(document, _)| *document == extension)
+            .map(|(_, entry)| *entry)
+            .collect();
+        if !entries.is_empty() {
+            return document_text(path, &entries, limit).map(Some);
+        }
+    }
+
+    let mut file = fs::File::open(path)?;
+    let mut contents = Vec::new();
+    file.by_ref()
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut contents)?;
+    if contents.contains(&0) {
+        return Ok(None);
+    }
+    file.read_to_end(&mut contents)?;
+    Ok(Some(String::from_utf8_lossy(&contents).into_owned()))
+}
+
+fn document_text(path: &Path, entries: &[&str], limit: u64) -> io::Result<String> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?)?;
+    let mut text = String::new();
+    // Entries can decompress to far more than the archive size, only the limit is read
+    let mut remaining = limit;
+    for index in 0..archive.len() {
+        if remaining == 0 {
+            break;
+        }
+        let file = archive.by_index(index)?;
+        if !entries.iter().any(|entry| file.name().starts_with(entry)) {
+            continue;
+        }
+        let mut xml = Vec::new();
+        remaining -= file.take(remaining).read_to_end(&mut xml)? as u64;
+        strip_tags(&String::from_utf8_lossy(&xml), &mut text);
+    }
+    Ok(text)
+}
+
+// Tags separate words, the entities left in do not matter to the fingerprint
+fn strip_tags(xml: &str, text: &mut String) {
+    let mut in_tag = false;
+    for c in xml.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+}
+
+fn simhash(text: &str) -> Option<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.len() < SHINGLE_WORDS {
+        return None;
+    }
+
+    let mut weights = [0i64; 64];
+    for shingle in words.windows(SHINGLE_WORDS) {
+        let hash = xxh3_64(shingle.join(" ").as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |fingerprint, (bit, _)| fingerprint | (1 << bit)),
+    )
+}
+
+fn is_image(path: &Path) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Difference hash of an image, resized, recompressed or converted copies giving close hashes
+pub fn image_hash(path: &Path, size: u64, config: &SimilarityConfig) -> Option<u64> {
+    if !config.images || !within_limit(size, config) || !is_image(path) {
+        return None;
+    }
+    let image = match image::open(path) {
+        Ok(image) => image,
+        Err(err) => {
+            debug!("Could not decode the image {}: {}", path.display(), err);
+            return None;
+        }
+    };
+    Some(dhash(&image))
+}
+
+// Each bit tells whether a pixel of the thumbnail is brighter than its right neighbour
+fn dhash(image: &DynamicImage) -> u64 {
+    let thumbnail = image
+        .grayscale()
+        .resize_exact(IMAGE_HASH_SIDE + 1, IMAGE_HASH_SIDE, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..IMAGE_HASH_SIDE {
+        for x in 0..IMAGE_HASH_SIDE {
+            hash <<= 1;
+            if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+/// Share of equal bits of two fingerprints, from 0 to 1
+pub fn similarity(a: u64, b: u64) -> f64 {
+    1.0 - f64::from((a ^ b).count_ones()) / 64.0
+}
+
+/// Fingerprints at least `threshold` similar differ by up to this many bits
+pub fn max_distance(threshold: f64) -> u32 {
+    ((1.0 - threshold.clamp(0.0, 1.0)) * 64.0).floor() as u32
+}
+
+/// Files whose fingerprints differ by few bits, linked through each other
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct NearDuplicateCluster {
+    /// Lowest similarity between two linked files
+    pub similarity: f64,
+    pub total_bytes: u64,
+    pub files: Vec<PathBuf>,
+}
+
+/// Groups the fingerprinted files, the biggest clusters first
+pub fn clusters(files: &[(PathBuf, u64, u64)], max_distance: u32) -> Vec<NearDuplicateCluster> {
+    // Fingerprints differing by up to `max_distance` bits have one of `bands` parts in common
+    let bands = (max_distance + 1).min(64);
+    let band_width = 64 / bands;
+
+    let mut parents: Vec<usize> = (0..files.len()).collect();
+    let mut lowest: HashMap<usize, f64> = HashMap::new();
+    for band in 0..bands {
+        let shift = band * band_width;
+        let mask = if band == bands - 1 {
+            u64::MAX >> shift
+        } else {
+            (1u64 << band_width) - 1
+        };
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, (_, _, fingerprint)) in files.iter().enumerate() {
+            buckets
+                .entry((fingerprint >> shift) & mask)
+                .or_default()
+                .push(index);
+        }
+        for bucket in buckets.values_mut().filter(|bucket| bucket.len() > 1) {
+            bucket.sort_unstable_by_key(|&index| files[index].2);
+            for (position, &a) in bucket.iter().enumerate() {
+                for &b in bucket[position + 1..].iter().take(BUCKET_NEIGHBOURS) {
+                    if (files[a].2 ^ files[b].2).count_ones() > max_distance {
+                        continue;
+                    }
+                    let pair_similarity = similarity(files[a].2, files[b].2);
+                    let (root_a, root_b) = (find(&mut parents, a), find(&mut parents, b));
+                    let linked = lowest
+                        .remove(&root_a)
+                        .into_iter()
+                        .chain(lowest.remove(&root_b))
+                        .fold(pair_similarity, f64::min);
+                    parents[root_b] = root_a;
+                    lowest.insert(root_a, linked);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..files.len() {
+        let root = find(&mut parents, index);
+        groups.entry(root).or_default().push(index);
+    }
+    let mut clusters: Vec<NearDuplicateCluster> = groups
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| {
+            let mut paths: Vec<PathBuf> = members.iter().map(|&i| files[i].0.clone()).collect();
+            paths.sort();
+            NearDuplicateCluster {
+                similarity: lowest.get(&root).copied().unwrap_or(1.0),
+                total_bytes: members.iter().map(|&i| files[i].1).sum(),
+                files: paths,
+            }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.files
+            .len()
+            .cmp(&a.files.len())
+            .then_with(|| b.total_bytes.cmp(&a.total_bytes))
+            .then_with(|| a.files.cmp(&b.files))
+    });
+    clusters
+}
+
+fn find(parents: &mut [usize], mut index: usize) -> usize {
+    while parents[index] != index {
+        parents[index] = parents[parents[index]];
+        index = parents[index];
+    }
+    index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use image::{Rgb, RgbImage};
+
+    const REPORT: &str = "The quarterly report shows that sales grew in every region, \
+        led by the northern stores which opened two new locations this spring. \
+        Costs stayed flat while the online shop doubled its orders compared to last year, \
+        and the team expects the same trend to carry on through the end of the year.";
+
+    #[test]
+    fn close_texts_cluster_together() {
+        let original = simhash(REPORT).unwrap();
+        let edited = simhash(&REPORT.replace("two new locations", "three new locations")).unwrap();
+        let other = simhash(
+            "Meeting notes: the build server is down again, please use the backup runner \
+            until the disk is replaced and remember to rotate the deploy keys on friday.",
+        )
+        .unwrap();
+        assert!(similarity(original, edited) > similarity(original, other));
+
+        let files = vec![
+            (PathBuf::from("report.txt"), 100, original),
+            (PathBuf::from("report(1).txt"), 100, edited),
+            (PathBuf::from("notes.txt"), 50, other),
+        ];
+        let clusters = clusters(&files, (original ^ edited).count_ones());
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(
+            clusters[0].files,
+            vec![PathBuf::from("report(1).txt"), PathBuf::from("report.txt")]
+        );
+        assert_eq!(clusters[0].total_bytes, 200);
+    }
+
+    #[test]
+    fn many_similar_files_cluster_in_linear_time() {
+        // The low bits differ, so most bands put every file in the same bucket
+        let files: Vec<(PathBuf, u64, u64)> = (0..5_000)
+            .map(|i| {
+                (
+                    PathBuf::from(format!("copy-{i}.txt")),
+                    10,
+                    0x0123_4567_89ab_0000 ^ i,
+                )
+            })
+            .collect();
+        let clusters = clusters(&files, MAX_IMAGE_DISTANCE);
+        assert_eq!(clusters.len(), 1);
+        assert_eq!(clusters[0].files.len(), files.len());
+    }
+
+    #[test]
+    fn documents_are_read_up_to_the_limit() {
+        use std::io::Write;
+
+        let dir = TempDir::new("similarity");
+        fs::create_dir_all(dir.path()).unwrap();
+        let path = dir.path().join("report.docx");
+        let mut archive = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        archive
+            .start_file("word/document.xml", Default::default())
+            .unwrap();
+        for _ in 0..10_000 {
+            archive.write_all(b"<w:t>same words again</w:t>").unwrap();
+        }
+        archive.finish().unwrap();
+
+        let text = document_text(&path, &["word/document.xml"], 1000).unwrap();
+        assert!(text.contains("same words again"));
+        assert!(text.len() <= 1000);
+    }
+
+    #[test]
+    fn resized_images_hash_alike() {
+        let gradient = RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, ((x * y) % 256) as u8])
+        });
+        let original = DynamicImage::ImageRgb8(gradient);
+        let resized = original.resize_exact(40, 30, FilterType::Lanczos3);
+        let flipped = original.fliph();
+
+        let distance = |a: &DynamicImage, b: &DynamicImage| (dhash(a) ^ dhash(b)).count_ones();
+        assert!(distance(&original, &resized) <= 4);
+        assert!(distance(&original, &flipped) > 16);
+    }
+}
@@ -0,0 +1,173 @@
+use directories::ProjectDirs;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::error::AgentError;
+
+// Where the agent used to keep its files, relative to the working directory or the executable
+const LEGACY_DIR: &str = "config";
+
+// A directory the agent did not create may be shared on purpose, so it is left as it is
+fn warn_if_shared(path: &Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.permissions().mode() & 0o077 != 0 => warn!(
+                "The state directory {} is open to other users, it holds the agent keypair",
+                path.display()
+            ),
+            Ok(_) => {}
+            Err(err) => warn!(
+                "Could not check the permissions of {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+}
+
+/// Creates `path` and its missing parents, readable by the owner only
+pub fn create_private(path: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(path)
+}
+
+/// Directory holding the agent id, its keypair and other local data
+#[derive(Debug, Clone)]
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    pub fn open(configured: Option<&Path>) -> Result<Self, AgentError> {
+        let path = match configured {
+            Some(path) => path.to_path_buf(),
+            None => default_dir(),
+        };
+        if path.is_dir() {
+            warn_if_shared(&path);
+        } else {
+            create_private(&path)?;
+        }
+        info!("Using state directory {}", path.display());
+        Ok(Self { path })
+    }
+
+    pub fn file(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Replaces `name` through a rename so readers never see a partial file, readable by the owner only
+    pub fn write_atomic(&self, name: &str, contents: &[u8]) -> Result<(), AgentError> {
+        let tmp_path = self.file(&format!(".{name}.tmp"));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let result = options.open(&tmp_path).and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        });
+        if let Err(err) = result.and_then(|_| fs::rename(&tmp_path, self.file(name))) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Opens `name` to add to it, creating it readable by the owner only
+    pub fn open_append(&self, name: &str) -> io::Result<fs::File> {
+        let mut options = fs::OpenOptions::new();
+        options.append(true).create(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options.open(self.file(name))
+    }
+
+    /// Moves `name` from the old `config/` locations into the state directory if it isn't there yet
+    pub fn migrate_legacy(&self, name: &str, is_valid: impl Fn(&[u8]) -> bool) {
+        if self.file(name).exists() {
+            return;
+        }
+        for legacy_path in legacy_paths(name) {
+            let contents = match fs::read(&legacy_path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            if !is_valid(&contents) {
+                warn!(
+                    "Ignoring invalid legacy file {}, not migrating it",
+                    legacy_path.display()
+                );
+                continue;
+            }
+            match self.write_atomic(name, &contents) {
+                Ok(()) => {
+                    info!(
+                        "Migrated {} to {}",
+                        legacy_path.display(),
+                        self.file(name).display()
+                    );
+                    if let Err(err) = fs::remove_file(&legacy_path) {
+                        warn!("Could not remove {}: {}", legacy_path.display(), err);
+                    }
+                    return;
+                }
+                Err(err) => warn!("Could not migrate {}: {}", legacy_path.display(), err),
+            }
+        }
+    }
+}
+
+fn legacy_paths(name: &str) -> Vec<PathBuf> {
+    let mut paths = vec![Path::new(LEGACY_DIR).join(name)];
+    if let Some(exe_dir) = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(Path::to_path_buf))
+    {
+        paths.push(exe_dir.join(LEGACY_DIR).join(name));
+    }
+    paths
+}
+
+/// XDG state dir on Linux, the platform data dir elsewhere, a system wide one when there is no home
+pub fn default_dir() -> PathBuf {
+    match ProjectDirs::from("com", "TidyBee", "tidybee-agent") {
+        Some(dirs) => dirs
+            .state_dir()
+            .unwrap_or_else(|| dirs.data_local_dir())
+            .to_path_buf(),
+        None => system_dir(),
+    }
+}
+
+#[cfg(windows)]
+fn system_dir() -> PathBuf {
+    let program_data = std::env::var_os("ProgramData").unwrap_or_else(|| r"C:\ProgramData".into());
+    PathBuf::from(program_data)
+        .join("TidyBee")
+        .join("tidybee-agent")
+}
+
+#[cfg(not(windows))]
+fn system_dir() -> PathBuf {
+    PathBuf::from("/var/lib/tidybee-agent")
+}
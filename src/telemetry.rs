@@ -0,0 +1,218 @@
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+// Also kept outside of the recorder, which can't be read back, for the status of the agent
+static PENDING_EVENTS: AtomicI64 = AtomicI64::new(0);
+static LAST_WATCHER_EVENT_MS: AtomicU64 = AtomicU64::new(0);
+static LAST_EVENT_SENT_MS: AtomicU64 = AtomicU64::new(0);
+static LAST_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static ACKNOWLEDGED_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+const FILES_SCANNED: &str = "tidybee_files_scanned_total";
+const SCAN_DURATION: &str = "tidybee_scan_duration_seconds";
+const BYTES_HASHED: &str = "tidybee_bytes_hashed_total";
+const HASH_DURATION: &str = "tidybee_hash_duration_seconds";
+const HASH_THROUGHPUT: &str = "tidybee_hash_throughput_bytes_per_second";
+const WATCHER_EVENTS: &str = "tidybee_watcher_events_total";
+const EVENT_QUEUE_DEPTH: &str = "tidybee_event_queue_depth";
+const EVENTS_SENT: &str = "tidybee_events_sent_total";
+const EVENTS_FAILED: &str = "tidybee_events_failed_total";
+const EVENTS_DROPPED: &str = "tidybee_events_dropped_total";
+const EVENTS_REJECTED: &str = "tidybee_events_rejected_total";
+const LAST_EVENT_SENT: &str = "tidybee_last_event_sent_timestamp_seconds";
+const EVENT_SEQUENCE: &str = "tidybee_event_sequence";
+const ACKNOWLEDGED_SEQUENCE_GAUGE: &str = "tidybee_acknowledged_sequence";
+const GRPC_DURATION: &str = "tidybee_grpc_request_duration_seconds";
+const HUB_RECONNECTS: &str = "tidybee_hub_reconnects_total";
+const HUB_CONNECTED: &str = "tidybee_hub_connected";
+
+const SCAN_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0];
+const HASH_BUCKETS: &[f64] = &[0.0001, 0.001, 0.01, 0.1, 0.5, 1.0, 5.0, 30.0];
+const GRPC_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Records the metrics of the whole process, rendered by the returned handle
+pub fn install() -> Option<PrometheusHandle> {
+    let recorder = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(SCAN_DURATION.to_owned()), SCAN_BUCKETS)
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(Matcher::Full(HASH_DURATION.to_owned()), HASH_BUCKETS)
+        })
+        .and_then(|builder| {
+            builder.set_buckets_for_metric(Matcher::Full(GRPC_DURATION.to_owned()), GRPC_BUCKETS)
+        })
+        .and_then(|builder| builder.install_recorder());
+    let handle = match recorder {
+        Ok(handle) => handle,
+        Err(err) => {
+            error!("Metrics are disabled: {}", err);
+            return None;
+        }
+    };
+
+    describe_counter!(
+        FILES_SCANNED,
+        "Files found while listing the watched directories"
+    );
+    describe_histogram!(SCAN_DURATION, "Time taken to list and hash directories");
+    describe_counter!(BYTES_HASHED, "Bytes read to hash files");
+    describe_histogram!(HASH_DURATION, "Time taken to hash a file");
+    describe_gauge!(
+        HASH_THROUGHPUT,
+        "Bytes listed and hashed per second by the last scan"
+    );
+    describe_counter!(
+        WATCHER_EVENTS,
+        "Events reported by the file watcher by kind"
+    );
+    describe_gauge!(
+        EVENT_QUEUE_DEPTH,
+        "Watcher events waiting to be sent to the Hub"
+    );
+    describe_counter!(EVENTS_SENT, "File events sent to the Hub");
+    describe_counter!(EVENTS_FAILED, "File events the Hub did not receive");
+    describe_counter!(
+        EVENTS_DROPPED,
+        "File events given up before being sent to the Hub"
+    );
+    describe_counter!(
+        EVENTS_REJECTED,
+        "File events the Hub refused to store for good"
+    );
+    describe_gauge!(
+        LAST_EVENT_SENT,
+        "Unix time of the last file event sent to the Hub"
+    );
+    describe_gauge!(
+        EVENT_SEQUENCE,
+        "Sequence of the last event numbered for the Hub"
+    );
+    describe_gauge!(
+        ACKNOWLEDGED_SEQUENCE_GAUGE,
+        "Sequence up to which the Hub holds every event"
+    );
+    describe_histogram!(
+        GRPC_DURATION,
+        "Duration of the gRPC calls to the Hub by method"
+    );
+    describe_counter!(HUB_RECONNECTS, "Failed attempts to connect to the Hub");
+    describe_gauge!(HUB_CONNECTED, "Whether the agent is connected to the Hub");
+    Some(handle)
+}
+
+pub fn scan_completed(files: usize, bytes: u64, duration: Duration) {
+    counter!(FILES_SCANNED).increment(files as u64);
+    histogram!(SCAN_DURATION).record(duration.as_secs_f64());
+    if !duration.is_zero() {
+        gauge!(HASH_THROUGHPUT).set(bytes as f64 / duration.as_secs_f64());
+    }
+}
+
+pub fn file_hashed(bytes: u64, duration: Duration) {
+    counter!(BYTES_HASHED).increment(bytes);
+    histogram!(HASH_DURATION).record(duration.as_secs_f64());
+}
+
+pub fn watcher_event(kind: &notify::EventKind) {
+    let kind = match kind {
+        notify::EventKind::Create(_) => "create",
+        notify::EventKind::Modify(_) => "modify",
+        notify::EventKind::Remove(_) => "remove",
+        notify::EventKind::Access(_) => "access",
+        _ => "other",
+    };
+    counter!(WATCHER_EVENTS, "kind" => kind).increment(1);
+    LAST_WATCHER_EVENT_MS.store(unix_millis(), Ordering::Relaxed);
+}
+
+pub fn event_queued() {
+    gauge!(EVENT_QUEUE_DEPTH).increment(1.0);
+    PENDING_EVENTS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn event_dequeued() {
+    gauge!(EVENT_QUEUE_DEPTH).decrement(1.0);
+    PENDING_EVENTS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Watcher events waiting to be sent to the Hub
+pub fn pending_events() -> u64 {
+    PENDING_EVENTS.load(Ordering::Relaxed).max(0) as u64
+}
+
+pub fn last_watcher_event() -> Option<SystemTime> {
+    from_unix_millis(LAST_WATCHER_EVENT_MS.load(Ordering::Relaxed))
+}
+
+pub fn last_event_sent() -> Option<SystemTime> {
+    from_unix_millis(LAST_EVENT_SENT_MS.load(Ordering::Relaxed))
+}
+
+pub fn event_sequence(sequence: u64) {
+    gauge!(EVENT_SEQUENCE).set(sequence as f64);
+    LAST_SEQUENCE.store(sequence, Ordering::Relaxed);
+}
+
+pub fn sequence_acknowledged(sequence: u64) {
+    let acknowledged = ACKNOWLEDGED_SEQUENCE
+        .fetch_max(sequence, Ordering::Relaxed)
+        .max(sequence);
+    gauge!(ACKNOWLEDGED_SEQUENCE_GAUGE).set(acknowledged as f64);
+}
+
+pub fn last_sequence() -> u64 {
+    LAST_SEQUENCE.load(Ordering::Relaxed)
+}
+
+pub fn acknowledged_sequence() -> u64 {
+    ACKNOWLEDGED_SEQUENCE.load(Ordering::Relaxed)
+}
+
+pub fn grpc_request(method: &'static str, duration: Duration) {
+    histogram!(GRPC_DURATION, "method" => method).record(duration.as_secs_f64());
+}
+
+pub fn grpc_call(method: &'static str, events: usize, duration: Duration, succeeded: bool) {
+    grpc_request(method, duration);
+    if succeeded {
+        counter!(EVENTS_SENT).increment(events as u64);
+        let now = unix_millis();
+        gauge!(LAST_EVENT_SENT).set(now as f64 / 1000.0);
+        LAST_EVENT_SENT_MS.store(now, Ordering::Relaxed);
+    } else {
+        counter!(EVENTS_FAILED).increment(events as u64);
+    }
+}
+
+pub fn event_dropped() {
+    counter!(EVENTS_DROPPED).increment(1);
+}
+
+pub fn event_rejected() {
+    counter!(EVENTS_REJECTED).increment(1);
+}
+
+pub fn hub_reconnect() {
+    counter!(HUB_RECONNECTS).increment(1);
+    gauge!(HUB_CONNECTED).set(0.0);
+}
+
+pub fn hub_connected() {
+    gauge!(HUB_CONNECTED).set(1.0);
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+// 0 stands for never
+fn from_unix_millis(millis: u64) -> Option<SystemTime> {
+    (millis > 0).then(|| UNIX_EPOCH + Duration::from_millis(millis))
+}
@@ -0,0 +1,25 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A directory for one test, removed with what it holds once dropped, so that a failed run leaves nothing behind
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// The directory is left for the test to create, such as through `StateDir::open`
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("tidybee-{}-{}", name, std::process::id()));
+        // Left over by a run that was killed
+        let _ = fs::remove_dir_all(&dir);
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::configuration::{Configuration, TlsConfig};
+use crate::http::tls::parse_pin;
+use crate::logging::parse_filter;
+use crate::similarity::{MAX_IMAGE_DISTANCE, MIN_THRESHOLD};
+use reqwest::Url;
+use std::fmt;
+use std::fs::read_dir;
+use std::net::SocketAddr;
+use std::path::Path;
+use tracing::{error, warn};
+
+pub const LOG_LEVELS: [&str; 5] = ["trace", "debug", "info", "warn", "error"];
+const PROTOCOLS: [&str; 2] = ["http", "https"];
+const MIN_API_KEY_LENGTH: usize = 16;
+const ROTATIONS: [&str; 4] = ["minutely", "hourly", "daily", "never"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in the configuration, tied to the key it comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}: {}: {}", severity, self.key, self.message)
+    }
+}
+
+pub fn error_count(diagnostics: &[Diagnostic]) -> usize {
+    diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count()
+}
+
+pub fn log_diagnostics(diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        match diagnostic.severity {
+            Severity::Warning => warn!("Configuration {}", diagnostic),
+            Severity::Error => error!("Configuration {}", diagnostic),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, key: &str, message: impl Into<String>) {
+        self.push(Severity::Error, key, message.into());
+    }
+
+    fn warning(&mut self, key: &str, message: impl Into<String>) {
+        self.push(Severity::Warning, key, message.into());
+    }
+
+    fn push(&mut self, severity: Severity, key: &str, message: String) {
+        self.0.push(Diagnostic {
+            severity,
+            key: key.to_owned(),
+            message,
+        });
+    }
+
+    fn log_level(&mut self, key: &str, level: &str) {
+        if !LOG_LEVELS.contains(&level) {
+            self.error(
+                key,
+                format!(
+                    "unknown log level {level:?}, expected one of {}",
+                    LOG_LEVELS.join(", ")
+                ),
+            );
+        }
+    }
+
+    fn directives(&mut self, key: &str, directives: &str) {
+        if let Err(err) = parse_filter(directives) {
+            self.error(
+                key,
+                format!("invalid log level or directives {directives:?}: {err}"),
+            );
+        }
+    }
+
+    fn protocol(&mut self, key: &str, protocol: &str) {
+        if !PROTOCOLS.contains(&protocol) {
+            self.error(
+                key,
+                format!("unknown protocol {protocol:?}, expected http or https"),
+            );
+        }
+    }
+
+    fn path(&mut self, key: &str, path: &str, needs_agent_id: bool) {
+        if !path.starts_with('/') {
+            self.error(key, format!("{path:?} must start with /"));
+        }
+        if needs_agent_id && !path.contains("{agent_id}") {
+            self.warning(
+                key,
+                format!("{path:?} has no {{agent_id}} placeholder, every agent will share it"),
+            );
+        }
+    }
+
+    fn file(&mut self, key: &str, path: &Path) {
+        if !path.is_file() {
+            self.error(key, format!("{} is not a readable file", path.display()));
+        }
+    }
+
+    fn tls(&mut self, key: &str, tls: &TlsConfig, protocol: &str) {
+        let is_set = tls.ca_cert.is_some()
+            || tls.client_cert.is_some()
+            || tls.client_key.is_some()
+            || tls.server_name.is_some()
+            || !tls.pinned_sha256.is_empty();
+        if is_set && protocol != "https" {
+            self.warning(key, "TLS settings are ignored unless the protocol is https");
+        }
+        if let Some(ca_cert) = &tls.ca_cert {
+            self.file(&format!("{key}.ca_cert"), ca_cert);
+        }
+        match (&tls.client_cert, &tls.client_key) {
+            (Some(cert), Some(key_path)) => {
+                self.file(&format!("{key}.client_cert"), cert);
+                self.file(&format!("{key}.client_key"), key_path);
+            }
+            (None, None) => {}
+            _ => self.error(key, "client_cert and client_key must be set together"),
+        }
+        for pin in &tls.pinned_sha256 {
+            if let Err(err) = parse_pin(pin) {
+                self.error(&format!("{key}.pinned_sha256"), err.to_string());
+            }
+        }
+    }
+}
+
+/// Checks the whole configuration, returning every problem instead of stopping at the first one
+pub fn validate(config: &Configuration) -> Vec<Diagnostic> {
+    let mut diagnostics = Diagnostics::default();
+
+    if config.server_config.address.parse::<SocketAddr>().is_err() {
+        diagnostics.error(
+            "server_config.address",
+            format!(
+                "{:?} is not a <ip>:<port> address",
+                config.server_config.address
+            ),
+        );
+    }
+    diagnostics.log_level("server_config.log_level", &config.server_config.log_level);
+    let auth = &config.server_config.auth;
+    for key in auth.read_keys.iter().chain(auth.admin_keys.iter()) {
+        if key.len() < MIN_API_KEY_LENGTH {
+            diagnostics.error(
+                "server_config.auth",
+                format!("API keys must be at least {MIN_API_KEY_LENGTH} characters long"),
+            );
+        }
+    }
+    diagnostics.directives("logger_config.term_level", &config.logger_config.term_level);
+    diagnostics.directives("logger_config.file_level", &config.logger_config.file_level);
+    if !ROTATIONS.contains(&config.logger_config.rotation.as_str()) {
+        diagnostics.error(
+            "logger_config.rotation",
+            format!(
+                "unknown rotation {:?}, expected one of {}",
+                config.logger_config.rotation,
+                ROTATIONS.join(", ")
+            ),
+        );
+    }
+
+    if config.filesystem_interface_config.dir.is_empty() {
+        diagnostics.warning("filesystem_interface_config.dir", "no directory to watch");
+    }
+    for dir in &config.filesystem_interface_config.dir {
+        if !dir.is_dir() {
+            diagnostics.error(
+                "filesystem_interface_config.dir",
+                format!("{} is not a directory", dir.display()),
+            );
+        } else if let Err(err) = read_dir(dir) {
+            diagnostics.error(
+                "filesystem_interface_config.dir",
+                format!("{} can't be listed: {}", dir.display(), err),
+            );
+        }
+    }
+
+    let hub = &config.hub_config;
+    diagnostics.protocol("hub_config.protocol", &hub.protocol);
+    if hub.port.parse::<u16>().is_err() {
+        diagnostics.error("hub_config.port", format!("{:?} is not a port", hub.port));
+    } else if Url::parse(&format!("{}://{}:{}", hub.protocol, hub.host, hub.port)).is_err()
+        || hub.host.is_empty()
+    {
+        diagnostics.error(
+            "hub_config.host",
+            format!("{:?} does not form a valid Hub URL", hub.host),
+        );
+    }
+    diagnostics.path("hub_config.auth_path", &hub.auth_path, false);
+    diagnostics.path("hub_config.disconnect_path", &hub.disconnect_path, true);
+    diagnostics.path("hub_config.challenge_path", &hub.challenge_path, true);
+    diagnostics.path("hub_config.token_path", &hub.token_path, true);
+    if hub.initial_backoff_ms == 0 {
+        diagnostics.error("hub_config.initial_backoff_ms", "must be greater than 0");
+    }
+    if hub.max_backoff_ms < hub.initial_backoff_ms {
+        diagnostics.error(
+            "hub_config.max_backoff_ms",
+            format!(
+                "{} is lower than initial_backoff_ms ({})",
+                hub.max_backoff_ms, hub.initial_backoff_ms
+            ),
+        );
+    }
+    if !(0.0..=1.0).contains(&hub.backoff_jitter) {
+        diagnostics.error(
+            "hub_config.backoff_jitter",
+            format!("{} is not between 0 and 1", hub.backoff_jitter),
+        );
+    }
+    diagnostics.tls("hub_config.tls", &hub.tls, &hub.protocol);
+
+    let grpc = &hub.grpc_server;
+    diagnostics.protocol("hub_config.grpc_server.protocol", &grpc.protocol);
+    if grpc.host.is_empty() {
+        diagnostics.error("hub_config.grpc_server.host", "must not be empty");
+    }
+    if grpc.port == 0 {
+        diagnostics.error("hub_config.grpc_server.port", "must not be 0");
+    }
+    diagnostics.log_level("hub_config.grpc_server.log_level", &grpc.log_level);
+    diagnostics.tls("hub_config.grpc_server.tls", &grpc.tls, &grpc.protocol);
+
+    let similarity = &config.similarity_config;
+    if !(MIN_THRESHOLD..=1.0).contains(&similarity.threshold) {
+        diagnostics.error(
+            "similarity_config.threshold",
+            format!(
+                "{} is not between {} and 1",
+                similarity.threshold, MIN_THRESHOLD
+            ),
+        );
+    }
+    if similarity.image_max_distance > MAX_IMAGE_DISTANCE {
+        diagnostics.error(
+            "similarity_config.image_max_distance",
+            format!(
+                "{} is above {}",
+                similarity.image_max_distance, MAX_IMAGE_DISTANCE
+            ),
+        );
+    }
+
+    let commands = &config.commands_config;
+    if commands.allow_watch_root_changes && commands.watch_root_parents.is_empty() {
+        diagnostics.warning(
+            "commands_config.watch_root_parents",
+            "the Hub can't add any watched directory",
+        );
+    }
+    for parent in &commands.watch_root_parents {
+        if !parent.is_dir() {
+            diagnostics.warning(
+                "commands_config.watch_root_parents",
+                format!("{} is not a directory", parent.display()),
+            );
+        }
+    }
+
+    if let Some(dir) = &config.state_config.dir {
+        if dir.exists() && !dir.is_dir() {
+            diagnostics.error(
+                "state_config.dir",
+                format!("{} is not a directory", dir.display()),
+            );
+        }
+    }
+
+    diagnostics.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_configuration_is_valid() {
+        assert_eq!(validate(&Configuration::default()), vec![]);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut config = Configuration::default();
+        config.server_config.address = "localhost".to_owned();
+        config.logger_config.file_level = "verbose".to_owned();
+        config.filesystem_interface_config.dir = vec!["does/not/exist".into()];
+        config.hub_config.port = "70000".to_owned();
+        config.hub_config.backoff_jitter = 2.0;
+        config.hub_config.token_path = "/token".to_owned();
+
+        let keys: Vec<(Severity, String)> = validate(&config)
+            .into_iter()
+            .map(|diagnostic| (diagnostic.severity, diagnostic.key))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                (Severity::Error, "server_config.address".to_owned()),
+                (Severity::Error, "logger_config.file_level".to_owned()),
+                (
+                    Severity::Error,
+                    "filesystem_interface_config.dir".to_owned()
+                ),
+                (Severity::Error, "hub_config.port".to_owned()),
+                (Severity::Warning, "hub_config.token_path".to_owned()),
+                (Severity::Error, "hub_config.backoff_jitter".to_owned()),
+            ]
+        );
+    }
+}